@@ -0,0 +1,225 @@
+use crate::auth::{self, Identity};
+use crate::error::ApiError;
+use crate::AppState;
+use actix_web::http::header::USER_AGENT;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// Entries returned by `GET /audit` when no `limit` is given.
+const DEFAULT_LIMIT: usize = 100;
+
+/// What happened on the MQTT side of an action.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MqttOutcome {
+    /// Refused or answered before anything was published.
+    #[default]
+    NotSent,
+    /// Acknowledged by the broker, or written to it for QoS 0.
+    Published,
+    /// Not acknowledged in time; it may still be delivered later.
+    Unacknowledged,
+    Failed,
+}
+
+/// One line of the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    #[serde(with = "humantime_serde")]
+    pub timestamp: SystemTime,
+    /// Name of the API key, `None` when authentication is disabled.
+    pub credential: Option<String>,
+    /// Address the request came from.
+    pub client_ip: Option<String>,
+    /// Client address forwarded by one of `http.trusted_proxies`.
+    pub forwarded_for: Option<String>,
+    pub user_agent: Option<String>,
+    pub device: String,
+    pub action: String,
+    pub mqtt: MqttOutcome,
+    /// HTTP status the request was answered with.
+    pub status: u16,
+    /// Error message returned to the client, if any.
+    pub error: Option<String>,
+    pub latency_ms: u64,
+    #[serde(skip, default = "Instant::now")]
+    started: Instant,
+}
+
+impl AuditEntry {
+    /// Starts an entry with the caller's details; the outcome is filled in
+    /// once the action finished.
+    pub fn new(req: &HttpRequest, trusted_proxies: &[IpAddr], device: &str, action: &str) -> Self {
+        let credential = req
+            .extensions()
+            .get::<Identity>()
+            .map(|identity| identity.name.clone());
+        AuditEntry {
+            timestamp: SystemTime::now(),
+            credential,
+            client_ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+            forwarded_for: auth::forwarded_ip(req, trusted_proxies).map(|ip| ip.to_string()),
+            user_agent: req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            device: device.to_string(),
+            action: action.to_string(),
+            mqtt: MqttOutcome::NotSent,
+            status: 0,
+            error: None,
+            latency_ms: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Records how the request was answered.
+    pub fn finish(&mut self, result: &Result<HttpResponse, ApiError>) {
+        self.latency_ms = self.started.elapsed().as_millis() as u64;
+        match result {
+            Ok(response) => self.status = response.status().as_u16(),
+            Err(e) => {
+                self.status = actix_web::ResponseError::status_code(e).as_u16();
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+/// Append-only JSON-lines file of device actions.
+pub struct AuditLog {
+    path: Option<PathBuf>,
+    /// Serializes appends so concurrent entries do not interleave.
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        AuditLog {
+            path,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Appends an entry. Failures are logged rather than returned: the
+    /// action has already happened by the time it is audited.
+    pub async fn record(&self, entry: &AuditEntry) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let mut line = match serde_json::to_vec(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to encode audit entry: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let _guard = self.lock.lock().await;
+        let result = async {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            file.write_all(&line).await?;
+            file.flush().await
+        }
+        .await;
+        if let Err(e) = result {
+            error!("Failed to write audit log {}: {}", path.display(), e);
+        }
+    }
+
+    /// Reads all entries, skipping lines that do not parse.
+    async fn read(path: &Path) -> std::io::Result<Vec<AuditEntry>> {
+        let contents = match tokio::fs::read_to_string(path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .filter_map(|(index, line)| match serde_json::from_str(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    warn!(
+                        "Skipping line {} of audit log {}: {}",
+                        index + 1,
+                        path.display(),
+                        e
+                    );
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    /// Only entries at or after this time (RFC 3339).
+    #[serde(default, with = "humantime_serde")]
+    since: Option<SystemTime>,
+    /// Only entries of one device.
+    device: Option<String>,
+    /// Most recent entries to return.
+    limit: Option<usize>,
+}
+
+/// `GET /audit`: the most recent matching entries, oldest first. Keys only
+/// see devices their scopes grant `audit` on.
+pub async fn query(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let path = data
+        .audit
+        .path()
+        .ok_or_else(|| ApiError::not_found("Audit log is disabled; set audit.path to enable it"))?;
+    let entries = AuditLog::read(path).await.map_err(|e| {
+        error!("Failed to read audit log {}: {}", path.display(), e);
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to read audit log: {}", e),
+        )
+    })?;
+
+    let identity = req.extensions().get::<Identity>().cloned();
+    let mut entries: Vec<_> = entries
+        .into_iter()
+        .filter(|entry| query.since.is_none_or(|since| entry.timestamp >= since))
+        .filter(|entry| {
+            query
+                .device
+                .as_ref()
+                .is_none_or(|device| &entry.device == device)
+        })
+        .filter(|entry| {
+            identity
+                .as_ref()
+                .is_none_or(|identity| identity.allows(&entry.device, "audit"))
+        })
+        .collect();
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    let skip = entries.len().saturating_sub(limit);
+    let entries = entries.split_off(skip);
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "entries": entries })))
+}
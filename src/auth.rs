@@ -0,0 +1,642 @@
+use crate::config::{AuthConfig, ClientCertConfig, Scope};
+use crate::error::ApiError;
+use crate::AppState;
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Extensions, Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, AUTHORIZATION, X_FORWARDED_FOR};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::rt::net::TcpStream;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpMessage, HttpRequest, ResponseError};
+use log::{info, warn};
+use ring::hmac;
+use rustls::pki_types::CertificateDer;
+use sha2::{Digest, Sha256};
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use x509_parser::extensions::GeneralName;
+
+/// Paths served without credentials so probes and scrapers keep working.
+const PUBLIC_PATHS: &[&str] = &["/health", "/health/live", "/health/ready", "/metrics"];
+
+/// Message of the 401 body, identical to the one the Envoy gateway returns.
+const UNAUTHORIZED: &str = "Unauthorized: Invalid or missing API key";
+
+/// Headers of an HMAC-signed request.
+const SIGNATURE_KEY: &str = "x-signature-key";
+const SIGNATURE_TIMESTAMP: &str = "x-signature-timestamp";
+const SIGNATURE_NONCE: &str = "x-signature-nonce";
+const SIGNATURE: &str = "x-signature";
+
+/// Longest accepted `x-signature-nonce`.
+const MAX_NONCE_LEN: usize = 128;
+
+/// The credential a request was authenticated with, available to handlers
+/// through request extensions.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub name: String,
+    pub credential: Credential,
+    pub scopes: Vec<Scope>,
+}
+
+/// How an identity was established.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Credential {
+    ApiKey,
+    ClientCertificate,
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.credential {
+            Credential::ApiKey => write!(f, "key '{}'", self.name),
+            Credential::ClientCertificate => write!(f, "client '{}'", self.name),
+        }
+    }
+}
+
+impl Identity {
+    pub fn allows(&self, device: &str, action: &str) -> bool {
+        self.scopes.iter().any(|scope| scope.allows(device, action))
+    }
+
+    /// Whether any scope grants some action on `device`.
+    pub fn can_access(&self, device: &str) -> bool {
+        self.scopes
+            .iter()
+            .any(|scope| scope.device.as_deref().is_none_or(|d| d == device))
+    }
+}
+
+/// Checks that the request's credential may perform `action` on `device`.
+/// Requests are unrestricted when authentication is disabled.
+pub fn authorize(req: &HttpRequest, device: &str, action: &str) -> Result<(), ApiError> {
+    let extensions = req.extensions();
+    let Some(identity) = extensions.get::<Identity>() else {
+        return Ok(());
+    };
+    if identity.allows(device, action) {
+        return Ok(());
+    }
+    warn!(
+        "Denied {} on device '{}' to {}: not in its scopes",
+        action, device, identity
+    );
+    Err(ApiError::new(
+        StatusCode::FORBIDDEN,
+        format!(
+            "Forbidden: {} may not {} device '{}'",
+            identity, action, device
+        ),
+    ))
+}
+
+/// The certificate an HTTPS client presented, kept with its connection.
+#[derive(Debug, Clone)]
+pub struct PeerCertificate(pub CertificateDer<'static>);
+
+/// Records the client certificate of an HTTPS connection for `authenticate`;
+/// the `on_connect` callback of the HTTP server.
+pub fn record_peer_certificate(connection: &dyn Any, extensions: &mut Extensions) {
+    let Some(tls) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+    if let Some(cert) = tls
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+    {
+        extensions.insert(PeerCertificate(cert.clone().into_owned()));
+    }
+}
+
+/// The address a request is attributed to: the client address a trusted
+/// proxy forwarded, the peer's otherwise.
+pub fn client_ip(req: &HttpRequest, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    forwarded_ip(req, trusted_proxies).or_else(|| req.peer_addr().map(|addr| addr.ip()))
+}
+
+/// The client address in `X-Forwarded-For`, if the peer is one of
+/// `http.trusted_proxies`. Only the last entry is used: the proxy appends
+/// the address it received the request from, anything before it was sent
+/// by the client.
+pub fn forwarded_ip(req: &HttpRequest, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    if !trusted_proxies.contains(&peer) {
+        return None;
+    }
+    req.headers()
+        .get_all(X_FORWARDED_FOR)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .last()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Subject and subject alternative names of a certificate, as matched
+/// against `auth.clients`.
+pub struct CertificateNames {
+    pub subject: String,
+    pub sans: Vec<String>,
+}
+
+impl CertificateNames {
+    pub fn parse(cert: &[u8]) -> Option<CertificateNames> {
+        let (_, parsed) = x509_parser::parse_x509_certificate(cert).ok()?;
+        let sans = match parsed.subject_alternative_name() {
+            Ok(Some(extension)) => extension
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => Some(name.to_string()),
+                    GeneralName::IPAddress(bytes) => match bytes.len() {
+                        4 => Some(IpAddr::from(<[u8; 4]>::try_from(*bytes).ok()?).to_string()),
+                        16 => Some(IpAddr::from(<[u8; 16]>::try_from(*bytes).ok()?).to_string()),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        Some(CertificateNames {
+            subject: parsed.subject().to_string(),
+            sans,
+        })
+    }
+}
+
+/// HTTPS clients known by their certificate.
+#[derive(Debug)]
+pub struct ClientCertificates {
+    clients: Vec<ClientCertConfig>,
+}
+
+impl ClientCertificates {
+    pub fn new(config: &AuthConfig) -> Self {
+        ClientCertificates {
+            clients: config.clients.clone(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// The first client whose subject and SAN, whichever are set, match.
+    pub fn identify(&self, names: &CertificateNames) -> Option<Identity> {
+        self.clients
+            .iter()
+            .find(|client| {
+                client
+                    .subject
+                    .as_ref()
+                    .is_none_or(|subject| *subject == names.subject)
+                    && client
+                        .san
+                        .as_ref()
+                        .is_none_or(|san| names.sans.contains(san))
+            })
+            .map(|client| Identity {
+                name: client.name.clone(),
+                credential: Credential::ClientCertificate,
+                scopes: client.scopes.clone(),
+            })
+    }
+}
+
+struct ApiKey {
+    name: String,
+    hash: Option<[u8; 32]>,
+    hmac: Option<hmac::Key>,
+    scopes: Vec<Scope>,
+    not_before: Option<SystemTime>,
+    not_after: Option<SystemTime>,
+}
+
+impl ApiKey {
+    /// Why the key cannot be used right now, if it is outside its window.
+    fn validity_error(&self, now: SystemTime) -> Option<&'static str> {
+        if self.not_before.is_some_and(|at| now < at) {
+            return Some("not yet valid");
+        }
+        if self.not_after.is_some_and(|at| now >= at) {
+            return Some("expired");
+        }
+        None
+    }
+}
+
+/// SHA-256 hashes of the accepted API keys and secrets of signing keys.
+pub struct ApiKeys {
+    keys: Vec<ApiKey>,
+    clients: Arc<ClientCertificates>,
+    max_clock_skew: Duration,
+    /// Nonces of accepted signed requests, by key name and nonce, with the
+    /// time after which their timestamp falls outside the clock skew.
+    nonces: Mutex<HashMap<(String, String), SystemTime>>,
+}
+
+impl ApiKeys {
+    pub fn new(config: &AuthConfig) -> Self {
+        let keys = config
+            .keys
+            .iter()
+            .map(|key| ApiKey {
+                name: key.name.clone(),
+                hash: key
+                    .key_sha256
+                    .as_deref()
+                    .and_then(|hash| hex::decode(hash).ok()?.try_into().ok()),
+                hmac: key
+                    .hmac_secret
+                    .as_ref()
+                    .map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())),
+                scopes: key.scopes.clone(),
+                not_before: key.not_before,
+                not_after: key.not_after,
+            })
+            .collect();
+        ApiKeys {
+            keys,
+            clients: Arc::new(ClientCertificates::new(config)),
+            max_clock_skew: config.max_clock_skew,
+            nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty() || !self.clients.is_empty()
+    }
+
+    pub fn clients(&self) -> Arc<ClientCertificates> {
+        self.clients.clone()
+    }
+
+    /// Finds the key matching `presented`. Every stored hash is compared in
+    /// constant time so the response time does not reveal which key was close.
+    fn verify(&self, presented: &str) -> Option<&ApiKey> {
+        let hash: [u8; 32] = Sha256::digest(presented.as_bytes()).into();
+        let mut found = None;
+        for key in &self.keys {
+            if key
+                .hash
+                .is_some_and(|stored| bool::from(stored.ct_eq(&hash)))
+            {
+                found = Some(key);
+            }
+        }
+        found
+    }
+
+    /// Records a nonce, returning false if it was already used with `key`.
+    fn remember_nonce(&self, key: &str, nonce: &str, expires: SystemTime) -> bool {
+        let now = SystemTime::now();
+        let mut nonces = self.nonces.lock().unwrap_or_else(|e| e.into_inner());
+        nonces.retain(|_, expiry| *expiry > now);
+        nonces
+            .insert((key.to_string(), nonce.to_string()), expires)
+            .is_none()
+    }
+}
+
+/// Why a request was not authenticated: `message` is returned in the 401
+/// body, `detail` is only logged.
+struct Rejection {
+    message: String,
+    detail: String,
+}
+
+impl Rejection {
+    fn new(message: impl Into<String>, detail: impl Into<String>) -> Self {
+        Rejection {
+            message: message.into(),
+            detail: detail.into(),
+        }
+    }
+
+    fn signature(detail: impl Into<String>) -> Self {
+        Rejection::new("Unauthorized: Invalid request signature", detail)
+    }
+}
+
+/// Reads the key from `x-api-key` or an `Authorization: Bearer` header.
+fn presented_key(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get("x-api-key") {
+        return key.to_str().ok();
+    }
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str, Rejection> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| Rejection::signature(format!("missing or malformed {} header", name)))
+}
+
+/// Verifies an HMAC-signed request. The signature is the hex-encoded
+/// HMAC-SHA256 of `METHOD\nPATH?QUERY\nTIMESTAMP\nNONCE\n` followed by the
+/// body, keyed with the key's `hmac_secret`.
+async fn verify_signature<'a>(
+    req: &mut ServiceRequest,
+    keys: &'a ApiKeys,
+) -> Result<&'a ApiKey, Rejection> {
+    let headers = req.headers();
+    let name = header(headers, SIGNATURE_KEY)?;
+    let timestamp = header(headers, SIGNATURE_TIMESTAMP)?;
+    let nonce = header(headers, SIGNATURE_NONCE)?.to_string();
+    let signature = hex::decode(header(headers, SIGNATURE)?)
+        .map_err(|_| Rejection::signature("signature is not hexadecimal"))?;
+
+    let (key, secret) = keys
+        .keys
+        .iter()
+        .find_map(|key| Some((key, key.hmac.as_ref()?)).filter(|(key, _)| key.name == name))
+        .ok_or_else(|| Rejection::signature(format!("no signing key named '{}'", name)))?;
+    if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+        return Err(Rejection::signature(format!(
+            "nonce must be 1 to {} characters",
+            MAX_NONCE_LEN
+        )));
+    }
+
+    let signed_at = UNIX_EPOCH
+        .checked_add(Duration::from_secs(timestamp.parse().map_err(|_| {
+            Rejection::signature("timestamp is not a number of Unix seconds")
+        })?))
+        .ok_or_else(|| Rejection::signature("timestamp out of range"))?;
+    let skew = match SystemTime::now().duration_since(signed_at) {
+        Ok(behind) => behind,
+        Err(ahead) => ahead.duration(),
+    };
+    if skew > keys.max_clock_skew {
+        return Err(Rejection::new(
+            "Unauthorized: Request timestamp outside the allowed clock skew",
+            format!(
+                "timestamp is {} off, more than {}",
+                humantime::format_duration(Duration::from_secs(skew.as_secs())),
+                humantime::format_duration(keys.max_clock_skew)
+            ),
+        ));
+    }
+
+    let mut message = format!(
+        "{}\n{}\n{}\n{}\n",
+        req.method(),
+        req.uri()
+            .path_and_query()
+            .map_or(req.path(), |pq| pq.as_str()),
+        timestamp,
+        nonce
+    )
+    .into_bytes();
+    // Handlers still need the body, so it is put back after reading it.
+    let body = req
+        .extract::<Bytes>()
+        .await
+        .map_err(|e| Rejection::signature(format!("cannot read body: {}", e)))?;
+    message.extend_from_slice(&body);
+    req.set_payload(Payload::from(body));
+
+    hmac::verify(secret, &message, &signature)
+        .map_err(|_| Rejection::signature(format!("signature mismatch for key '{}'", key.name)))?;
+    let expires = signed_at
+        .checked_add(keys.max_clock_skew)
+        .ok_or_else(|| Rejection::signature("timestamp out of range"))?;
+    if !keys.remember_nonce(&key.name, &nonce, expires) {
+        return Err(Rejection::new(
+            "Unauthorized: Request already processed",
+            format!("nonce '{}' of key '{}' was replayed", nonce, key.name),
+        ));
+    }
+    Ok(key)
+}
+
+/// Middleware rejecting requests without a valid API key or signature with
+/// 401.
+pub async fn authenticate(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let Some(data) = req.app_data::<web::Data<AppState>>().cloned() else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    };
+    if !data.api_keys.is_enabled() || PUBLIC_PATHS.contains(&req.path()) {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    }
+
+    let identity = match req.conn_data::<PeerCertificate>() {
+        // The handshake already refused certificates of unknown clients.
+        Some(PeerCertificate(cert)) => CertificateNames::parse(cert)
+            .and_then(|names| data.api_keys.clients.identify(&names))
+            .ok_or_else(|| Rejection::new(UNAUTHORIZED, "unknown client certificate")),
+        None => if req.headers().contains_key(SIGNATURE) {
+            verify_signature(&mut req, &data.api_keys).await
+        } else {
+            presented_key(req.headers())
+                .and_then(|presented| data.api_keys.verify(presented))
+                .ok_or_else(|| Rejection::new(UNAUTHORIZED, "invalid or missing API key"))
+        }
+        .and_then(|key| match key.validity_error(SystemTime::now()) {
+            Some(reason) => Err(Rejection::new(
+                format!("Unauthorized: API key {}", reason),
+                format!("API key '{}' is {}", key.name, reason),
+            )),
+            None => Ok(Identity {
+                name: key.name.clone(),
+                credential: Credential::ApiKey,
+                scopes: key.scopes.clone(),
+            }),
+        }),
+    };
+
+    match identity {
+        Ok(identity) => {
+            info!(
+                "{} {} authenticated as {}",
+                req.method(),
+                req.path(),
+                identity
+            );
+            req.extensions_mut().insert(identity);
+            next.call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        }
+        Err(rejection) => {
+            warn!(
+                "Rejected {} {} from {}: {}",
+                req.method(),
+                req.path(),
+                client_ip(req.request(), &data.config.http.trusted_proxies)
+                    .map_or_else(|| "unknown".to_string(), |ip| ip.to_string()),
+                rejection.detail
+            );
+            let response =
+                ApiError::new(StatusCode::UNAUTHORIZED, rejection.message).error_response();
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    const SECRET: &str = "signing-secret";
+
+    fn keys() -> ApiKeys {
+        let config: AuthConfig = toml::from_str(&format!(
+            "max_clock_skew = \"5m\"\n[[keys]]\nname = \"shortcut\"\nhmac_secret = \"{}\"\n",
+            SECRET
+        ))
+        .unwrap();
+        ApiKeys::new(&config)
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn sign(path: &str, timestamp: &str, nonce: &str, body: &str) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, SECRET.as_bytes());
+        let message = format!("POST\n{}\n{}\n{}\n{}", path, timestamp, nonce, body);
+        hex::encode(hmac::sign(&key, message.as_bytes()))
+    }
+
+    /// A request to `path` with `body`, carrying `signature` whatever it was
+    /// computed over.
+    fn request(
+        path: &str,
+        body: &str,
+        timestamp: &str,
+        nonce: &str,
+        signature: String,
+    ) -> ServiceRequest {
+        TestRequest::post()
+            .uri(path)
+            .insert_header((SIGNATURE_KEY, "shortcut"))
+            .insert_header((SIGNATURE_TIMESTAMP, timestamp))
+            .insert_header((SIGNATURE_NONCE, nonce))
+            .insert_header((SIGNATURE, signature))
+            .set_payload(body.to_string())
+            .to_srv_request()
+    }
+
+    fn signed(path: &str, body: &str, timestamp: &str, nonce: &str) -> ServiceRequest {
+        request(
+            path,
+            body,
+            timestamp,
+            nonce,
+            sign(path, timestamp, nonce, body),
+        )
+    }
+
+    async fn verify(mut req: ServiceRequest, keys: &ApiKeys) -> Result<String, String> {
+        verify_signature(&mut req, keys)
+            .await
+            .map(|key| key.name.clone())
+            .map_err(|rejection| rejection.detail)
+    }
+
+    #[actix_web::test]
+    async fn accepts_valid_signature() {
+        let keys = keys();
+        let timestamp = now().to_string();
+        let req = signed("/devices/garage/trigger?wait=10s", "{}", &timestamp, "n1");
+        assert_eq!(verify(req, &keys).await, Ok("shortcut".to_string()));
+    }
+
+    #[actix_web::test]
+    async fn keeps_body_for_handlers() {
+        let keys = keys();
+        let timestamp = now().to_string();
+        let mut req = signed("/garage", "payload", &timestamp, "n1");
+        verify_signature(&mut req, &keys).await.ok().unwrap();
+        let body = req.extract::<Bytes>().await.unwrap();
+        assert_eq!(body, "payload");
+    }
+
+    #[actix_web::test]
+    async fn rejects_tampered_body() {
+        let keys = keys();
+        let timestamp = now().to_string();
+        let signature = sign("/garage", "", &timestamp, "n1");
+        let req = request("/garage", "tampered", &timestamp, "n1", signature);
+        let detail = verify(req, &keys).await.unwrap_err();
+        assert!(detail.contains("signature mismatch"), "{}", detail);
+    }
+
+    #[actix_web::test]
+    async fn rejects_tampered_path() {
+        let keys = keys();
+        let timestamp = now().to_string();
+        let signature = sign("/devices/garage/close", "", &timestamp, "n1");
+        let req = request("/devices/garage/open", "", &timestamp, "n1", signature);
+        let detail = verify(req, &keys).await.unwrap_err();
+        assert!(detail.contains("signature mismatch"), "{}", detail);
+    }
+
+    #[actix_web::test]
+    async fn rejects_replayed_nonce() {
+        let keys = keys();
+        let timestamp = now().to_string();
+        let first = signed("/garage", "", &timestamp, "n1");
+        assert!(verify(first, &keys).await.is_ok());
+        let replay = signed("/garage", "", &timestamp, "n1");
+        let detail = verify(replay, &keys).await.unwrap_err();
+        assert!(detail.contains("replayed"), "{}", detail);
+    }
+
+    #[actix_web::test]
+    async fn rejects_timestamp_outside_clock_skew() {
+        let keys = keys();
+        for timestamp in [now() - 301, now() + 301] {
+            let req = signed("/garage", "", &timestamp.to_string(), "n1");
+            let detail = verify(req, &keys).await.unwrap_err();
+            assert!(detail.contains("more than 5m"), "{}", detail);
+        }
+    }
+
+    #[actix_web::test]
+    async fn rejects_oversized_timestamp() {
+        let keys = keys();
+        let timestamp = u64::MAX.to_string();
+        let req = signed("/garage", "", &timestamp, "n1");
+        assert_eq!(
+            verify(req, &keys).await,
+            Err("timestamp out of range".to_string())
+        );
+    }
+}
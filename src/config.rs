@@ -0,0 +1,1407 @@
+use crate::routes::Action;
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Environment variable pointing at the TOML configuration file.
+pub const CONFIG_PATH_VAR: &str = "CONFIG_PATH";
+
+/// Prefix for environment variables overriding individual config keys,
+/// e.g. `BRIDGE__MQTT__PORT=1883` or `BRIDGE__DEVICES__GATE__TOPIC=gate/trigger`.
+const ENV_PREFIX: &str = "BRIDGE__";
+
+/// Environment variables from before the config file existed, mapped to the
+/// key they override. `devices.default` refers to the default device.
+const LEGACY_ENV_VARS: &[(&str, &str)] = &[
+    ("MQTT_HOST", "mqtt.host"),
+    ("MQTT_PORT", "mqtt.port"),
+    ("CA_CERT_PATH", "mqtt.tls.ca_cert"),
+    ("CLIENT_CERT_PATH", "mqtt.tls.client_cert"),
+    ("CLIENT_KEY_PATH", "mqtt.tls.client_key"),
+    ("HTTP_PORT", "http.port"),
+    ("API_KEY", "auth.api_key"),
+    ("API_KEYS", "auth.api_keys"),
+    ("MQTT_TOPIC", "devices.default.topic"),
+    ("MQTT_PAYLOAD", "devices.default.payload"),
+];
+
+/// Name given to the device created from `MQTT_TOPIC`/`MQTT_PAYLOAD` when the
+/// config file declares none.
+const LEGACY_DEVICE_NAME: &str = "garage";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("cannot read config file {}: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid config file {}: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("environment variable {var} sets `{key}`: {message}")]
+    Env {
+        var: String,
+        key: String,
+        message: String,
+    },
+    #[error("invalid value for `{key}`: {message}")]
+    Invalid { key: String, message: String },
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Device served by the legacy `/garage` route. Defaults to the first
+    /// device declared.
+    #[serde(default)]
+    pub default_device: Option<String>,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub mqtt: MqttConfig,
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub audit: AuditConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub bind: String,
+    pub port: u16,
+    /// Reverse proxies whose `X-Forwarded-For` header is believed. Requests
+    /// from anywhere else are attributed to their peer address.
+    pub trusted_proxies: Vec<IpAddr>,
+    pub tls: HttpTlsConfig,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            bind: "0.0.0.0".to_string(),
+            port: 8080,
+            trusted_proxies: Vec::new(),
+            tls: HttpTlsConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpTlsConfig {
+    /// Certificate chain served on `http.port`, which speaks plain HTTP
+    /// if unset.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// Passphrase of an encrypted PKCS#8 `key`.
+    pub key_passphrase: Option<String>,
+    /// File holding the passphrase. Trailing newlines are ignored.
+    pub key_passphrase_file: Option<PathBuf>,
+    /// How often the certificate and key are checked for changes; zero
+    /// disables reloading.
+    #[serde(with = "humantime_serde")]
+    pub reload_interval: Duration,
+    /// Port of a plain HTTP listener redirecting every request to HTTPS.
+    pub redirect_port: Option<u16>,
+    /// CA verifying client certificates. Certificates it did not issue, or
+    /// that match none of `auth.clients`, are refused during the handshake.
+    pub client_ca: Option<PathBuf>,
+    /// Refuse connections without a client certificate instead of falling
+    /// back to API keys.
+    pub require_client_cert: bool,
+}
+
+impl Default for HttpTlsConfig {
+    fn default() -> Self {
+        HttpTlsConfig {
+            cert: None,
+            key: None,
+            key_passphrase: None,
+            key_passphrase_file: None,
+            reload_interval: Duration::from_secs(60),
+            redirect_port: None,
+            client_ca: None,
+            require_client_cert: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    /// Broker hostname. Has no default: a bridge pointed at nowhere should
+    /// refuse to start rather than try `mqtt.example.com`.
+    pub host: String,
+    /// Port of `host`, and of `brokers` that do not set their own.
+    pub port: u16,
+    /// Brokers to fail over between, instead of `host`.
+    pub brokers: Vec<BrokerConfig>,
+    /// How often the bridge, while connected to a less preferred broker,
+    /// checks whether a preferred one is reachable again. Zero disables.
+    #[serde(with = "humantime_serde")]
+    pub fallback_interval: Duration,
+    pub protocol: Protocol,
+    /// Client id presented to the broker. Replicas sharing a broker need
+    /// distinct ids, see `client_id_suffix`.
+    pub client_id: String,
+    pub client_id_suffix: ClientIdSuffix,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// File holding the password, e.g. a mounted secret. Trailing newlines
+    /// are ignored.
+    pub password_file: Option<PathBuf>,
+    #[serde(with = "humantime_serde")]
+    pub keep_alive: Duration,
+    /// How long an action waits for the broker to acknowledge its publish.
+    #[serde(with = "humantime_serde")]
+    pub ack_timeout: Duration,
+    /// How long startup waits for the first ConnAck before the HTTP server
+    /// is started, or the bridge gives up in strict mode.
+    #[serde(with = "humantime_serde")]
+    pub startup_timeout: Duration,
+    pub startup_mode: StartupMode,
+    /// MQTT 5: topic devices send their replies to. The bridge subscribes
+    /// to it and matches replies to requests by their correlation data.
+    pub response_topic: Option<String>,
+    /// Topic announcing whether the bridge is connected: a retained
+    /// `online` after every ConnAck, and `offline` as the Last Will.
+    pub availability_topic: Option<String>,
+    pub reconnect: ReconnectConfig,
+    pub tls: MqttTlsConfig,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            host: String::new(),
+            port: 8883,
+            brokers: Vec::new(),
+            fallback_interval: Duration::from_secs(300),
+            protocol: Protocol::default(),
+            client_id: "garage-mqtt-bridge".to_string(),
+            client_id_suffix: ClientIdSuffix::default(),
+            username: None,
+            password: None,
+            password_file: None,
+            keep_alive: Duration::from_secs(30),
+            ack_timeout: Duration::from_secs(10),
+            startup_timeout: Duration::from_secs(10),
+            startup_mode: StartupMode::default(),
+            response_topic: None,
+            availability_topic: None,
+            reconnect: ReconnectConfig::default(),
+            tls: MqttTlsConfig::default(),
+        }
+    }
+}
+
+/// MQTT protocol version spoken to the broker.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum Protocol {
+    #[default]
+    #[serde(rename = "3.1.1")]
+    V311,
+    #[serde(rename = "5")]
+    V5,
+}
+
+impl std::str::FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "3.1.1" => Ok(Protocol::V311),
+            "5" => Ok(Protocol::V5),
+            other => Err(format!(
+                "protocol must be \"3.1.1\" or \"5\", got '{}'",
+                other
+            )),
+        }
+    }
+}
+
+/// What is appended to `mqtt.client_id`, separated by a `-`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientIdSuffix {
+    #[default]
+    None,
+    /// The host name, e.g. the pod name on Kubernetes.
+    Hostname,
+    /// Random hex, chosen once at startup.
+    Random,
+}
+
+impl std::str::FromStr for ClientIdSuffix {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(ClientIdSuffix::None),
+            "hostname" => Ok(ClientIdSuffix::Hostname),
+            "random" => Ok(ClientIdSuffix::Random),
+            other => Err(format!(
+                "client_id_suffix must be \"none\", \"hostname\" or \"random\", got '{}'",
+                other
+            )),
+        }
+    }
+}
+
+/// One of `mqtt.brokers`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BrokerConfig {
+    pub host: String,
+    /// Defaults to `mqtt.port`.
+    pub port: Option<u16>,
+    /// Lower is preferred; brokers of equal priority are tried in the order
+    /// they are listed.
+    #[serde(default)]
+    pub priority: u32,
+}
+
+/// How the event loop backs off while the broker is unreachable.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectConfig {
+    /// Delay after the first failure, doubled after each further one.
+    #[serde(with = "humantime_serde")]
+    pub initial_delay: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max_delay: Duration,
+    /// Consecutive failures after which the circuit opens and actions fail
+    /// without trying to publish.
+    pub circuit_threshold: u32,
+    /// How often an ongoing outage is summarized in the log.
+    #[serde(with = "humantime_serde")]
+    pub summary_interval: Duration,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(120),
+            circuit_threshold: 3,
+            summary_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What the bridge does when the broker is not connected within
+/// `mqtt.startup_timeout`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StartupMode {
+    /// Serve HTTP anyway, not ready until the broker connects.
+    #[default]
+    Lenient,
+    /// Exit without serving HTTP.
+    Strict,
+}
+
+impl std::str::FromStr for StartupMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "lenient" => Ok(StartupMode::Lenient),
+            "strict" => Ok(StartupMode::Strict),
+            other => Err(format!(
+                "startup mode must be \"lenient\" or \"strict\", got '{}'",
+                other
+            )),
+        }
+    }
+}
+
+/// How the connection to the broker is secured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// TLS with a client certificate.
+    #[default]
+    Mutual,
+    /// TLS verifying the broker only; authenticate with username/password.
+    Server,
+    /// Plaintext, for local development.
+    Disabled,
+}
+
+impl std::str::FromStr for TlsMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "mutual" => Ok(TlsMode::Mutual),
+            "server" => Ok(TlsMode::Server),
+            "disabled" => Ok(TlsMode::Disabled),
+            other => Err(format!(
+                "tls mode must be \"mutual\", \"server\" or \"disabled\", got '{}'",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttTlsConfig {
+    pub mode: TlsMode,
+    pub ca_cert: PathBuf,
+    pub client_cert: PathBuf,
+    pub client_key: PathBuf,
+    /// PKCS#12 bundle holding the client certificate and key, used instead
+    /// of `client_cert` and `client_key`.
+    pub client_p12: Option<PathBuf>,
+    /// Passphrase of an encrypted PKCS#8 `client_key` or of `client_p12`.
+    pub key_passphrase: Option<String>,
+    /// File holding the passphrase. Trailing newlines are ignored.
+    pub key_passphrase_file: Option<PathBuf>,
+    /// How often the certificate files are checked for changes; zero
+    /// disables reloading.
+    #[serde(with = "humantime_serde")]
+    pub reload_interval: Duration,
+    /// Days before a certificate expires at which a warning is logged.
+    pub expiry_warning_days: Vec<u32>,
+    /// Report not ready once the client certificate expires within this
+    /// many days.
+    pub unready_within_days: Option<u32>,
+}
+
+impl Default for MqttTlsConfig {
+    fn default() -> Self {
+        MqttTlsConfig {
+            mode: TlsMode::default(),
+            ca_cert: PathBuf::from("/certs/ca.crt"),
+            client_cert: PathBuf::from("/certs/client.crt"),
+            client_key: PathBuf::from("/certs/client.key"),
+            client_p12: None,
+            key_passphrase: None,
+            key_passphrase_file: None,
+            reload_interval: Duration::from_secs(60),
+            expiry_warning_days: vec![30, 14, 7, 1],
+            unready_within_days: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// JSON-lines file every device action is appended to. Auditing is
+    /// disabled if unset.
+    pub path: Option<PathBuf>,
+}
+
+/// Token bucket applied to device actions per API key, or per client IP
+/// when authentication is disabled.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Actions a client may send in a row before being limited.
+    pub burst: u32,
+    /// Actions per minute the bucket refills by; 0 disables rate limiting.
+    pub per_minute: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            burst: 5,
+            per_minute: 10,
+        }
+    }
+}
+
+/// API keys accepted by the bridge. Authentication is enabled as soon as at
+/// least one key is configured.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// TOML file with further `[[keys]]` entries, e.g. a mounted secret.
+    pub keys_file: Option<PathBuf>,
+    pub keys: Vec<ApiKeyConfig>,
+    /// HTTPS clients authenticating with a certificate issued by
+    /// `http.tls.client_ca`.
+    pub clients: Vec<ClientCertConfig>,
+    /// How far the timestamp of a signed request may be from the bridge's
+    /// clock, in either direction.
+    #[serde(with = "humantime_serde")]
+    pub max_clock_skew: Duration,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            keys_file: None,
+            keys: Vec::new(),
+            clients: Vec::new(),
+            max_clock_skew: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    /// Name recorded in logs for requests made with this key.
+    pub name: String,
+    /// Plaintext key. Replaced by its hash as soon as the config is loaded.
+    #[serde(default)]
+    key: Option<String>,
+    /// Hex-encoded SHA-256 of the key.
+    #[serde(default)]
+    pub key_sha256: Option<String>,
+    /// Shared secret for HMAC-signed requests, which never send the secret
+    /// itself.
+    #[serde(default)]
+    pub hmac_secret: Option<String>,
+    /// What the key may do, as `device:action` with `*` wildcards.
+    /// Unrestricted if omitted.
+    #[serde(default = "unrestricted")]
+    pub scopes: Vec<Scope>,
+    /// Start of the validity window (RFC 3339), for temporary guest keys.
+    #[serde(default, with = "humantime_serde")]
+    pub not_before: Option<SystemTime>,
+    /// End of the validity window (RFC 3339).
+    #[serde(default, with = "humantime_serde")]
+    pub not_after: Option<SystemTime>,
+}
+
+impl ApiKeyConfig {
+    fn plaintext(name: &str, key: &str) -> Self {
+        ApiKeyConfig {
+            name: name.to_string(),
+            key: Some(key.to_string()),
+            key_sha256: None,
+            hmac_secret: None,
+            scopes: unrestricted(),
+            not_before: None,
+            not_after: None,
+        }
+    }
+}
+
+/// A client certificate accepted on the HTTPS listener. Its subject and SAN,
+/// whichever are set, must both match.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientCertConfig {
+    /// Name recorded in logs and the audit log for requests made with the
+    /// certificate.
+    pub name: String,
+    /// Subject distinguished name as logged by the bridge, e.g.
+    /// `CN=wall-panel, O=Home`.
+    #[serde(default)]
+    pub subject: Option<String>,
+    /// Subject alternative name: a DNS name, email address, URI or IP
+    /// address.
+    #[serde(default)]
+    pub san: Option<String>,
+    /// What the client may do, as `device:action` with `*` wildcards.
+    /// Unrestricted if omitted.
+    #[serde(default = "unrestricted")]
+    pub scopes: Vec<Scope>,
+}
+
+fn unrestricted() -> Vec<Scope> {
+    vec![Scope {
+        device: None,
+        action: None,
+    }]
+}
+
+/// Shortest accepted `hmac_secret`.
+const MIN_HMAC_SECRET_LEN: usize = 16;
+
+/// Actions a scope can grant.
+pub const SCOPE_ACTIONS: &[&str] = &["trigger", "open", "close", "state", "audit"];
+
+/// A `device:action` permission; `None` stands for the `*` wildcard.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Scope {
+    pub device: Option<String>,
+    pub action: Option<String>,
+}
+
+impl Scope {
+    pub fn allows(&self, device: &str, action: &str) -> bool {
+        self.device.as_deref().is_none_or(|d| d == device)
+            && self.action.as_deref().is_none_or(|a| a == action)
+    }
+}
+
+impl TryFrom<String> for Scope {
+    type Error = String;
+
+    fn try_from(scope: String) -> Result<Self, Self::Error> {
+        if scope == "*" {
+            return Ok(Scope {
+                device: None,
+                action: None,
+            });
+        }
+        let (device, action) = scope
+            .split_once(':')
+            .ok_or_else(|| format!("scope '{}' must have the form device:action", scope))?;
+        let wildcard = |part: &str| (part != "*").then(|| part.to_string());
+        if action != "*" && !SCOPE_ACTIONS.contains(&action) {
+            return Err(format!(
+                "unknown action '{}' in scope '{}', expected one of {} or *",
+                action,
+                scope,
+                SCOPE_ACTIONS.join(", ")
+            ));
+        }
+        Ok(Scope {
+            device: wildcard(device),
+            action: wildcard(action),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeysFile {
+    #[serde(default)]
+    keys: Vec<ApiKeyConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub name: String,
+    pub topic: String,
+    #[serde(default = "default_payload")]
+    pub payload: String,
+    #[serde(default)]
+    pub qos: Qos,
+    #[serde(default)]
+    pub retain: bool,
+    /// Payload for `/open`; the toggle `payload` is sent if unset.
+    #[serde(default)]
+    pub open_payload: Option<String>,
+    /// Payload for `/close`; the toggle `payload` is sent if unset.
+    #[serde(default)]
+    pub close_payload: Option<String>,
+    /// Topic the device reports its door state on (`open`, `closed`, ...).
+    #[serde(default)]
+    pub state_topic: Option<String>,
+    /// Minimum time between two commands sent to the device.
+    #[serde(default, with = "humantime_serde")]
+    pub cooldown: Duration,
+    /// MQTT 5: how long the broker keeps an undelivered command.
+    #[serde(default, with = "humantime_serde")]
+    pub message_expiry: Option<Duration>,
+    /// MQTT 5: content type of the payload.
+    #[serde(default)]
+    pub content_type: Option<String>,
+    /// MQTT 5: user properties sent with every command.
+    #[serde(default)]
+    pub user_properties: BTreeMap<String, String>,
+    /// MQTT 5: ask the device to reply on `mqtt.response_topic` and wait
+    /// this long for the reply.
+    #[serde(default, with = "humantime_serde")]
+    pub response_timeout: Option<Duration>,
+}
+
+fn default_payload() -> String {
+    "1".to_string()
+}
+
+impl DeviceConfig {
+    fn named(name: &str) -> Self {
+        DeviceConfig {
+            name: name.to_string(),
+            topic: String::new(),
+            payload: default_payload(),
+            qos: Qos::default(),
+            retain: false,
+            open_payload: None,
+            close_payload: None,
+            state_topic: None,
+            cooldown: Duration::ZERO,
+            message_expiry: None,
+            content_type: None,
+            user_properties: BTreeMap::new(),
+            response_timeout: None,
+        }
+    }
+
+    /// Payload published for an action.
+    pub fn payload_for(&self, action: Action) -> &str {
+        let specific = match action {
+            Action::Trigger => None,
+            Action::Open => self.open_payload.as_deref(),
+            Action::Close => self.close_payload.as_deref(),
+        };
+        specific.unwrap_or(&self.payload)
+    }
+}
+
+/// MQTT quality of service level as written in the config file (0, 1 or 2).
+/// Variant names mirror `rumqttc::QoS`.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum Qos {
+    AtMostOnce,
+    #[default]
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl TryFrom<u8> for Qos {
+    type Error = String;
+
+    fn try_from(level: u8) -> Result<Self, Self::Error> {
+        match level {
+            0 => Ok(Qos::AtMostOnce),
+            1 => Ok(Qos::AtLeastOnce),
+            2 => Ok(Qos::ExactlyOnce),
+            other => Err(format!("QoS must be 0, 1 or 2, got {}", other)),
+        }
+    }
+}
+
+impl From<Qos> for u8 {
+    fn from(qos: Qos) -> u8 {
+        match qos {
+            Qos::AtMostOnce => 0,
+            Qos::AtLeastOnce => 1,
+            Qos::ExactlyOnce => 2,
+        }
+    }
+}
+
+impl From<Qos> for QoS {
+    fn from(qos: Qos) -> QoS {
+        match qos {
+            Qos::AtMostOnce => QoS::AtMostOnce,
+            Qos::AtLeastOnce => QoS::AtLeastOnce,
+            Qos::ExactlyOnce => QoS::ExactlyOnce,
+        }
+    }
+}
+
+impl From<Qos> for rumqttc::v5::mqttbytes::QoS {
+    fn from(qos: Qos) -> Self {
+        match qos {
+            Qos::AtMostOnce => Self::AtMostOnce,
+            Qos::AtLeastOnce => Self::AtLeastOnce,
+            Qos::ExactlyOnce => Self::ExactlyOnce,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file named by `CONFIG_PATH` (if set), applies
+    /// environment overrides and validates the result.
+    pub fn load() -> Result<Config, ConfigError> {
+        let path = std::env::var_os(CONFIG_PATH_VAR).map(PathBuf::from);
+        // Variables that are not valid Unicode cannot be bridge settings.
+        let env = std::env::vars_os()
+            .filter_map(|(var, value)| Some((var.into_string().ok()?, value.into_string().ok()?)));
+        Config::from_sources(path.as_deref(), env)
+    }
+
+    pub fn from_sources(
+        path: Option<&Path>,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Config, ConfigError> {
+        let mut config = match path {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        // Legacy variables are applied first so the explicit BRIDGE__ form wins.
+        let mut overrides: Vec<(String, String, String)> = Vec::new();
+        let mut prefixed = Vec::new();
+        for (var, value) in env {
+            if let Some((_, key)) = LEGACY_ENV_VARS.iter().find(|(name, _)| *name == var) {
+                overrides.push((var, key.to_string(), value));
+            } else if let Some(rest) = var.strip_prefix(ENV_PREFIX) {
+                let key = rest
+                    .split("__")
+                    .collect::<Vec<_>>()
+                    .join(".")
+                    .to_lowercase();
+                prefixed.push((var, key, value));
+            }
+        }
+        prefixed.sort();
+        overrides.extend(prefixed);
+
+        for (var, key, value) in overrides {
+            config
+                .set(&key, &value)
+                .map_err(|message| ConfigError::Env { var, key, message })?;
+        }
+
+        if let Some(path) = config.auth.keys_file.clone() {
+            let keys: KeysFile = read_toml(&path)?;
+            config.auth.keys.extend(keys.keys);
+        }
+        config.auth.hash_keys()?;
+        config.mqtt.read_password_file()?;
+        config.mqtt.tls.read_passphrase_file()?;
+        config.http.tls.read_passphrase_file()?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        read_toml(path)
+    }
+
+    /// The device served by the legacy `/garage` route.
+    pub fn default_device(&self) -> &DeviceConfig {
+        self.default_device
+            .as_deref()
+            .and_then(|name| self.device(name))
+            .unwrap_or(&self.devices[0])
+    }
+
+    pub fn device(&self, name: &str) -> Option<&DeviceConfig> {
+        self.devices.iter().find(|d| d.name == name)
+    }
+
+    /// Overrides a single dotted key with a string value from the environment.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let parts: Vec<&str> = key.split('.').collect();
+        match parts.as_slice() {
+            ["default_device"] => self.default_device = Some(value.to_string()),
+            ["auth", "keys_file"] => self.auth.keys_file = Some(PathBuf::from(value)),
+            ["auth", "max_clock_skew"] => self.auth.max_clock_skew = parse_duration(value)?,
+            ["auth", "api_key"] => self
+                .auth
+                .keys
+                .push(ApiKeyConfig::plaintext("default", value)),
+            ["auth", "api_keys"] => {
+                for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+                    let (name, key) = entry
+                        .split_once(':')
+                        .ok_or_else(|| "expected a comma-separated list of name:key".to_string())?;
+                    self.auth.keys.push(ApiKeyConfig::plaintext(name, key));
+                }
+            }
+            ["http", "bind"] => self.http.bind = value.to_string(),
+            ["http", "port"] => self.http.port = parse(value)?,
+            ["http", "trusted_proxies"] => {
+                self.http.trusted_proxies = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|ip| !ip.is_empty())
+                    .map(parse)
+                    .collect::<Result<_, _>>()?
+            }
+            ["http", "tls", "cert"] => self.http.tls.cert = Some(PathBuf::from(value)),
+            ["http", "tls", "key"] => self.http.tls.key = Some(PathBuf::from(value)),
+            ["http", "tls", "key_passphrase"] => {
+                self.http.tls.key_passphrase = Some(value.to_string())
+            }
+            ["http", "tls", "key_passphrase_file"] => {
+                self.http.tls.key_passphrase_file = Some(PathBuf::from(value))
+            }
+            ["http", "tls", "reload_interval"] => {
+                self.http.tls.reload_interval = parse_duration(value)?
+            }
+            ["http", "tls", "redirect_port"] => self.http.tls.redirect_port = Some(parse(value)?),
+            ["http", "tls", "client_ca"] => self.http.tls.client_ca = Some(PathBuf::from(value)),
+            ["http", "tls", "require_client_cert"] => {
+                self.http.tls.require_client_cert = parse(value)?
+            }
+            ["mqtt", "host"] => self.mqtt.host = value.to_string(),
+            ["mqtt", "port"] => self.mqtt.port = parse(value)?,
+            ["mqtt", "brokers"] => {
+                self.mqtt.brokers.clear();
+                for (priority, entry) in value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|e| !e.is_empty())
+                    .enumerate()
+                {
+                    let (host, port) = match entry.rsplit_once(':') {
+                        Some((host, port)) => (host, Some(parse(port)?)),
+                        None => (entry, None),
+                    };
+                    self.mqtt.brokers.push(BrokerConfig {
+                        host: host.to_string(),
+                        port,
+                        priority: priority as u32,
+                    });
+                }
+            }
+            ["mqtt", "fallback_interval"] => self.mqtt.fallback_interval = parse_duration(value)?,
+            ["audit", "path"] => self.audit.path = Some(PathBuf::from(value)),
+            ["rate_limit", "burst"] => self.rate_limit.burst = parse(value)?,
+            ["rate_limit", "per_minute"] => self.rate_limit.per_minute = parse(value)?,
+            ["mqtt", "protocol"] => self.mqtt.protocol = parse(value)?,
+            ["mqtt", "client_id"] => self.mqtt.client_id = value.to_string(),
+            ["mqtt", "client_id_suffix"] => self.mqtt.client_id_suffix = parse(value)?,
+            ["mqtt", "username"] => self.mqtt.username = Some(value.to_string()),
+            ["mqtt", "password"] => self.mqtt.password = Some(value.to_string()),
+            ["mqtt", "password_file"] => self.mqtt.password_file = Some(PathBuf::from(value)),
+            ["mqtt", "keep_alive"] => self.mqtt.keep_alive = parse_duration(value)?,
+            ["mqtt", "ack_timeout"] => self.mqtt.ack_timeout = parse_duration(value)?,
+            ["mqtt", "startup_timeout"] => self.mqtt.startup_timeout = parse_duration(value)?,
+            ["mqtt", "startup_mode"] => self.mqtt.startup_mode = parse(value)?,
+            ["mqtt", "response_topic"] => self.mqtt.response_topic = Some(value.to_string()),
+            ["mqtt", "availability_topic"] => {
+                self.mqtt.availability_topic = Some(value.to_string())
+            }
+            ["mqtt", "reconnect", "initial_delay"] => {
+                self.mqtt.reconnect.initial_delay = parse_duration(value)?
+            }
+            ["mqtt", "reconnect", "max_delay"] => {
+                self.mqtt.reconnect.max_delay = parse_duration(value)?
+            }
+            ["mqtt", "reconnect", "circuit_threshold"] => {
+                self.mqtt.reconnect.circuit_threshold = parse(value)?
+            }
+            ["mqtt", "reconnect", "summary_interval"] => {
+                self.mqtt.reconnect.summary_interval = parse_duration(value)?
+            }
+            ["mqtt", "tls", "mode"] => self.mqtt.tls.mode = parse(value)?,
+            ["mqtt", "tls", "ca_cert"] => self.mqtt.tls.ca_cert = PathBuf::from(value),
+            ["mqtt", "tls", "client_cert"] => self.mqtt.tls.client_cert = PathBuf::from(value),
+            ["mqtt", "tls", "client_key"] => self.mqtt.tls.client_key = PathBuf::from(value),
+            ["mqtt", "tls", "client_p12"] => self.mqtt.tls.client_p12 = Some(PathBuf::from(value)),
+            ["mqtt", "tls", "key_passphrase"] => {
+                self.mqtt.tls.key_passphrase = Some(value.to_string())
+            }
+            ["mqtt", "tls", "key_passphrase_file"] => {
+                self.mqtt.tls.key_passphrase_file = Some(PathBuf::from(value))
+            }
+            ["mqtt", "tls", "reload_interval"] => {
+                self.mqtt.tls.reload_interval = parse_duration(value)?
+            }
+            ["mqtt", "tls", "expiry_warning_days"] => {
+                self.mqtt.tls.expiry_warning_days = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|days| !days.is_empty())
+                    .map(parse)
+                    .collect::<Result<_, _>>()?
+            }
+            ["mqtt", "tls", "unready_within_days"] => {
+                self.mqtt.tls.unready_within_days = Some(parse(value)?)
+            }
+            ["devices", name, field] => {
+                let device = self.device_mut(name);
+                match *field {
+                    "topic" => device.topic = value.to_string(),
+                    "payload" => device.payload = value.to_string(),
+                    "qos" => device.qos = Qos::try_from(parse::<u8>(value)?)?,
+                    "retain" => device.retain = parse(value)?,
+                    "open_payload" => device.open_payload = Some(value.to_string()),
+                    "close_payload" => device.close_payload = Some(value.to_string()),
+                    "state_topic" => device.state_topic = Some(value.to_string()),
+                    "cooldown" => device.cooldown = parse_duration(value)?,
+                    "message_expiry" => device.message_expiry = Some(parse_duration(value)?),
+                    "content_type" => device.content_type = Some(value.to_string()),
+                    "response_timeout" => device.response_timeout = Some(parse_duration(value)?),
+                    _ => return Err("unknown configuration key".to_string()),
+                }
+            }
+            ["devices", name, "user_properties", property] => {
+                self.device_mut(name)
+                    .user_properties
+                    .insert(property.to_string(), value.to_string());
+            }
+            _ => return Err("unknown configuration key".to_string()),
+        }
+        Ok(())
+    }
+
+    /// Finds the device an environment override refers to, declaring it if
+    /// it does not exist yet. `default` addresses the default device.
+    fn device_mut(&mut self, name: &str) -> &mut DeviceConfig {
+        let name = match (name, &self.default_device) {
+            ("default", Some(default)) => default.clone(),
+            ("default", None) => {
+                if self.devices.is_empty() {
+                    self.devices.push(DeviceConfig::named(LEGACY_DEVICE_NAME));
+                }
+                self.devices[0].name.clone()
+            }
+            (name, _) => name.to_string(),
+        };
+        match self.devices.iter().position(|d| d.name == name) {
+            Some(index) => &mut self.devices[index],
+            None => {
+                self.devices.push(DeviceConfig::named(&name));
+                self.devices.last_mut().unwrap()
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let tls = &self.http.tls;
+        if tls.cert.is_some() != tls.key.is_some() {
+            return Err(invalid(
+                if tls.cert.is_some() {
+                    "http.tls.key"
+                } else {
+                    "http.tls.cert"
+                },
+                "http.tls.cert and http.tls.key must be set together",
+            ));
+        }
+        if tls.client_ca.is_some() {
+            if !tls.is_enabled() {
+                return Err(invalid("http.tls.client_ca", "requires http.tls.cert"));
+            }
+            if self.auth.clients.is_empty() {
+                return Err(invalid(
+                    "http.tls.client_ca",
+                    "requires at least one auth.clients entry",
+                ));
+            }
+        } else {
+            if tls.require_client_cert {
+                return Err(invalid(
+                    "http.tls.require_client_cert",
+                    "requires http.tls.client_ca",
+                ));
+            }
+            if !self.auth.clients.is_empty() {
+                return Err(invalid("auth.clients", "requires http.tls.client_ca"));
+            }
+        }
+        if let Some(port) = tls.redirect_port {
+            if !tls.is_enabled() {
+                return Err(invalid("http.tls.redirect_port", "requires http.tls.cert"));
+            }
+            if port == self.http.port {
+                return Err(invalid(
+                    "http.tls.redirect_port",
+                    "must differ from http.port",
+                ));
+            }
+        }
+        if self.mqtt.brokers.is_empty() {
+            if self.mqtt.host.trim().is_empty() {
+                return Err(invalid("mqtt.host", "broker hostname is required"));
+            }
+        } else if !self.mqtt.host.is_empty() {
+            return Err(invalid(
+                "mqtt.host",
+                "cannot be combined with mqtt.brokers; list every broker there",
+            ));
+        }
+        let mut brokers = HashSet::new();
+        for (index, broker) in self.mqtt.brokers.iter().enumerate() {
+            let key = |field: &str| format!("mqtt.brokers[{}].{}", index, field);
+            if broker.host.trim().is_empty() {
+                return Err(invalid(&key("host"), "broker hostname is required"));
+            }
+            let port = broker.port.unwrap_or(self.mqtt.port);
+            if port == 0 {
+                return Err(invalid(&key("port"), "port must not be 0"));
+            }
+            if !brokers.insert((broker.host.as_str(), port)) {
+                return Err(invalid(
+                    &key("host"),
+                    &format!("duplicate broker '{}:{}'", broker.host, port),
+                ));
+            }
+        }
+        if self.mqtt.port == 0 {
+            return Err(invalid("mqtt.port", "port must not be 0"));
+        }
+        if self.mqtt.client_id.is_empty() {
+            return Err(invalid("mqtt.client_id", "must not be empty"));
+        }
+        if self.mqtt.username.as_deref() == Some("") {
+            return Err(invalid("mqtt.username", "must not be empty"));
+        }
+        if self.mqtt.password.is_some() && self.mqtt.username.is_none() {
+            return Err(invalid("mqtt.password", "requires mqtt.username"));
+        }
+        let reconnect = &self.mqtt.reconnect;
+        if reconnect.initial_delay.is_zero() {
+            return Err(invalid(
+                "mqtt.reconnect.initial_delay",
+                "must be greater than zero",
+            ));
+        }
+        if reconnect.max_delay < reconnect.initial_delay {
+            return Err(invalid(
+                "mqtt.reconnect.max_delay",
+                "must not be less than mqtt.reconnect.initial_delay",
+            ));
+        }
+        if reconnect.circuit_threshold == 0 {
+            return Err(invalid(
+                "mqtt.reconnect.circuit_threshold",
+                "must be at least 1",
+            ));
+        }
+        if reconnect.summary_interval.is_zero() {
+            return Err(invalid(
+                "mqtt.reconnect.summary_interval",
+                "must be greater than zero",
+            ));
+        }
+        if self.mqtt.ack_timeout.is_zero() {
+            return Err(invalid("mqtt.ack_timeout", "must be greater than zero"));
+        }
+        if self.mqtt.startup_timeout.is_zero() {
+            return Err(invalid("mqtt.startup_timeout", "must be greater than zero"));
+        }
+        if let Some(topic) = &self.mqtt.response_topic {
+            if self.mqtt.protocol != Protocol::V5 {
+                return Err(invalid(
+                    "mqtt.response_topic",
+                    "requires MQTT 5; set mqtt.protocol = \"5\"",
+                ));
+            }
+            validate_publish_topic(topic)
+                .map_err(|message| invalid("mqtt.response_topic", &message))?;
+        }
+        if let Some(topic) = &self.mqtt.availability_topic {
+            validate_publish_topic(topic)
+                .map_err(|message| invalid("mqtt.availability_topic", &message))?;
+        }
+        if self.devices.is_empty() {
+            return Err(invalid("devices", "at least one device must be configured"));
+        }
+
+        let mut names = HashSet::new();
+        for (index, device) in self.devices.iter().enumerate() {
+            let key = |field: &str| format!("devices[{}].{}", index, field);
+            if device.name.is_empty()
+                || !device
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+            {
+                return Err(invalid(
+                    &key("name"),
+                    "must be non-empty and contain only lowercase letters, digits, '-' or '_'",
+                ));
+            }
+            if !names.insert(device.name.as_str()) {
+                return Err(invalid(
+                    &key("name"),
+                    &format!("duplicate device name '{}'", device.name),
+                ));
+            }
+            validate_publish_topic(&device.topic)
+                .map_err(|message| invalid(&key("topic"), &message))?;
+            if let Some(topic) = &device.state_topic {
+                validate_publish_topic(topic)
+                    .map_err(|message| invalid(&key("state_topic"), &message))?;
+            }
+            self.validate_publish_options(index, device)?;
+        }
+
+        if self.rate_limit.per_minute > 0 && self.rate_limit.burst == 0 {
+            return Err(invalid("rate_limit.burst", "must be at least 1"));
+        }
+        if self.auth.max_clock_skew.is_zero() {
+            return Err(invalid("auth.max_clock_skew", "must be greater than zero"));
+        }
+        let mut key_names = HashSet::new();
+        for (index, key) in self.auth.keys.iter().enumerate() {
+            let field = |field: &str| format!("auth.keys[{}].{}", index, field);
+            if key.name.is_empty() {
+                return Err(invalid(&field("name"), "key name is required"));
+            }
+            if !key_names.insert(key.name.as_str()) {
+                return Err(invalid(
+                    &field("name"),
+                    &format!("duplicate key name '{}'", key.name),
+                ));
+            }
+            match key.key_sha256.as_deref().map(hex::decode) {
+                Some(Ok(hash)) if hash.len() == 32 => {}
+                Some(_) => {
+                    return Err(invalid(
+                        &field("key_sha256"),
+                        "must be 64 hexadecimal characters",
+                    ))
+                }
+                None if key.hmac_secret.is_some() => {}
+                None => {
+                    return Err(invalid(
+                        &field("key"),
+                        "one of key, key_sha256 or hmac_secret is required",
+                    ))
+                }
+            }
+            if key
+                .hmac_secret
+                .as_ref()
+                .is_some_and(|secret| secret.len() < MIN_HMAC_SECRET_LEN)
+            {
+                return Err(invalid(
+                    &field("hmac_secret"),
+                    &format!("must be at least {} characters", MIN_HMAC_SECRET_LEN),
+                ));
+            }
+            for (scope_index, scope) in key.scopes.iter().enumerate() {
+                if let Some(device) = &scope.device {
+                    if self.device(device).is_none() {
+                        return Err(invalid(
+                            &format!("auth.keys[{}].scopes[{}]", index, scope_index),
+                            &format!("no device named '{}' is configured", device),
+                        ));
+                    }
+                }
+            }
+            if let (Some(not_before), Some(not_after)) = (key.not_before, key.not_after) {
+                if not_after <= not_before {
+                    return Err(invalid(
+                        &field("not_after"),
+                        "must be later than not_before",
+                    ));
+                }
+            }
+        }
+
+        for (index, client) in self.auth.clients.iter().enumerate() {
+            let field = |field: &str| format!("auth.clients[{}].{}", index, field);
+            if client.name.is_empty() {
+                return Err(invalid(&field("name"), "client name is required"));
+            }
+            if !key_names.insert(client.name.as_str()) {
+                return Err(invalid(
+                    &field("name"),
+                    &format!("duplicate key or client name '{}'", client.name),
+                ));
+            }
+            if client.subject.is_none() && client.san.is_none() {
+                return Err(invalid(
+                    &field("subject"),
+                    "one of subject or san is required",
+                ));
+            }
+            for (scope_index, scope) in client.scopes.iter().enumerate() {
+                if let Some(device) = &scope.device {
+                    if self.device(device).is_none() {
+                        return Err(invalid(
+                            &format!("auth.clients[{}].scopes[{}]", index, scope_index),
+                            &format!("no device named '{}' is configured", device),
+                        ));
+                    }
+                }
+            }
+        }
+
+        if let Some(name) = &self.default_device {
+            if self.device(name).is_none() {
+                return Err(invalid(
+                    "default_device",
+                    &format!("no device named '{}' is configured", name),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the MQTT 5 publish options of a device, which a 3.1.1
+    /// connection cannot send.
+    fn validate_publish_options(
+        &self,
+        index: usize,
+        device: &DeviceConfig,
+    ) -> Result<(), ConfigError> {
+        let key = |field: &str| format!("devices[{}].{}", index, field);
+        let v5_options = [
+            ("message_expiry", device.message_expiry.is_some()),
+            ("content_type", device.content_type.is_some()),
+            ("user_properties", !device.user_properties.is_empty()),
+            ("response_timeout", device.response_timeout.is_some()),
+        ];
+        if self.mqtt.protocol != Protocol::V5 {
+            if let Some((field, _)) = v5_options.iter().find(|(_, set)| *set) {
+                return Err(invalid(
+                    &key(field),
+                    "requires MQTT 5; set mqtt.protocol = \"5\"",
+                ));
+            }
+        }
+        if let Some(expiry) = device.message_expiry {
+            if expiry.subsec_nanos() != 0
+                || expiry.is_zero()
+                || expiry.as_secs() > u64::from(u32::MAX)
+            {
+                return Err(invalid(
+                    &key("message_expiry"),
+                    "must be a whole number of seconds between 1s and 136 years",
+                ));
+            }
+        }
+        if device.content_type.as_deref() == Some("") {
+            return Err(invalid(&key("content_type"), "must not be empty"));
+        }
+        if device.user_properties.keys().any(String::is_empty) {
+            return Err(invalid(
+                &key("user_properties"),
+                "property names must not be empty",
+            ));
+        }
+        if let Some(timeout) = device.response_timeout {
+            if timeout.is_zero() {
+                return Err(invalid(
+                    &key("response_timeout"),
+                    "must be greater than zero",
+                ));
+            }
+            if self.mqtt.response_topic.is_none() {
+                return Err(invalid(
+                    &key("response_timeout"),
+                    "requires mqtt.response_topic to be set",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MqttConfig {
+    /// Replaces `password_file` by the password it holds.
+    fn read_password_file(&mut self) -> Result<(), ConfigError> {
+        if let Some(path) = self.password_file.take() {
+            self.password = Some(read_secret_file(
+                "mqtt.password_file",
+                &path,
+                "mqtt.password",
+                self.password.is_some(),
+            )?);
+        }
+        Ok(())
+    }
+}
+
+impl MqttTlsConfig {
+    /// Replaces `key_passphrase_file` by the passphrase it holds.
+    fn read_passphrase_file(&mut self) -> Result<(), ConfigError> {
+        if let Some(path) = self.key_passphrase_file.take() {
+            self.key_passphrase = Some(read_secret_file(
+                "mqtt.tls.key_passphrase_file",
+                &path,
+                "mqtt.tls.key_passphrase",
+                self.key_passphrase.is_some(),
+            )?);
+        }
+        Ok(())
+    }
+}
+
+impl HttpTlsConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.cert.is_some()
+    }
+
+    /// Replaces `key_passphrase_file` by the passphrase it holds.
+    fn read_passphrase_file(&mut self) -> Result<(), ConfigError> {
+        if let Some(path) = self.key_passphrase_file.take() {
+            self.key_passphrase = Some(read_secret_file(
+                "http.tls.key_passphrase_file",
+                &path,
+                "http.tls.key_passphrase",
+                self.key_passphrase.is_some(),
+            )?);
+        }
+        Ok(())
+    }
+}
+
+/// Reads the secret in the file `path` set as `key`, which replaces the
+/// inline setting `inline_key`.
+fn read_secret_file(
+    key: &str,
+    path: &Path,
+    inline_key: &str,
+    inline_set: bool,
+) -> Result<String, ConfigError> {
+    if inline_set {
+        return Err(invalid(
+            key,
+            &format!("cannot be combined with {}", inline_key),
+        ));
+    }
+    let secret = std::fs::read_to_string(path)
+        .map_err(|e| invalid(key, &format!("cannot read {}: {}", path.display(), e)))?;
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
+
+impl AuthConfig {
+    /// Replaces plaintext keys by their SHA-256 so they are not kept in memory.
+    fn hash_keys(&mut self) -> Result<(), ConfigError> {
+        for (index, key) in self.keys.iter_mut().enumerate() {
+            if let Some(plaintext) = key.key.take() {
+                if key.key_sha256.is_some() {
+                    return Err(invalid(
+                        &format!("auth.keys[{}]", index),
+                        "set either key or key_sha256, not both",
+                    ));
+                }
+                key.key_sha256 = Some(hex::encode(Sha256::digest(plaintext.as_bytes())));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_toml<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, ConfigError> {
+    let contents = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    toml::from_str(&contents).map_err(|source| ConfigError::Parse {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Checks a topic the bridge publishes to or matches exactly.
+fn validate_publish_topic(topic: &str) -> Result<(), String> {
+    if topic.is_empty() {
+        return Err("topic is required".to_string());
+    }
+    if topic.contains(['+', '#']) {
+        return Err(format!("wildcards are not allowed in topic '{}'", topic));
+    }
+    Ok(())
+}
+
+fn invalid(key: &str, message: &str) -> ConfigError {
+    ConfigError::Invalid {
+        key: key.to_string(),
+        message: message.to_string(),
+    }
+}
+
+fn parse<T>(value: &str) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("cannot parse '{}': {}", value, e))
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    humantime::parse_duration(value).map_err(|e| format!("cannot parse '{}': {}", value, e))
+}
@@ -0,0 +1,141 @@
+use crate::config::MqttConfig;
+use crate::health::ConnectionStatus;
+use log::{debug, info};
+use std::fmt;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+
+/// How long a preferred broker has to accept a TCP connection to count as
+/// reachable again.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A broker endpoint the bridge may connect to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Broker {
+    pub host: String,
+    pub port: u16,
+    /// Lower is preferred.
+    pub priority: u32,
+}
+
+impl fmt::Display for Broker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+/// The configured brokers, most preferred first, and the one in use.
+#[derive(Debug, Clone)]
+pub struct Brokers {
+    brokers: Vec<Broker>,
+    active: usize,
+}
+
+impl Brokers {
+    /// `mqtt.brokers`, or `mqtt.host` alone if none are listed.
+    pub fn new(config: &MqttConfig) -> Self {
+        let mut brokers: Vec<_> = if config.brokers.is_empty() {
+            vec![Broker {
+                host: config.host.clone(),
+                port: config.port,
+                priority: 0,
+            }]
+        } else {
+            config
+                .brokers
+                .iter()
+                .map(|broker| Broker {
+                    host: broker.host.clone(),
+                    port: broker.port.unwrap_or(config.port),
+                    priority: broker.priority,
+                })
+                .collect()
+        };
+        // Stable, so brokers of equal priority keep their configured order.
+        brokers.sort_by_key(|broker| broker.priority);
+        Brokers { brokers, active: 0 }
+    }
+
+    pub fn all(&self) -> &[Broker] {
+        &self.brokers
+    }
+
+    pub fn active(&self) -> &Broker {
+        &self.brokers[self.active]
+    }
+
+    /// Moves on to the next broker, from the least preferred back to the
+    /// most preferred one. Returns `None` if there is no other broker.
+    pub fn fail_over(&mut self) -> Option<&Broker> {
+        if self.brokers.len() < 2 {
+            return None;
+        }
+        self.active = (self.active + 1) % self.brokers.len();
+        Some(self.active())
+    }
+
+    /// Makes `broker` the active one, if it is configured.
+    pub fn switch_to(&mut self, broker: &Broker) {
+        if let Some(index) = self.brokers.iter().position(|b| b == broker) {
+            self.active = index;
+        }
+    }
+}
+
+/// While the event loop is connected to a broker other than the most
+/// preferred ones, checks every `mqtt.fallback_interval` whether a broker of
+/// a better priority accepts connections again, and sends the best one found
+/// to the event loop to fall back to.
+pub async fn watch_preferred(
+    brokers: Brokers,
+    interval: Duration,
+    connection: watch::Receiver<ConnectionStatus>,
+    fallbacks: mpsc::Sender<Broker>,
+) {
+    if brokers.all().len() < 2 || interval.is_zero() {
+        return;
+    }
+    info!(
+        "Checking every {} whether a preferred MQTT broker is reachable again",
+        humantime::format_duration(interval)
+    );
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    // The first tick completes immediately.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let active = {
+            let status = connection.borrow();
+            // While disconnected, failing over already tries every broker.
+            match &status.broker {
+                Some(broker) if status.connected => broker.clone(),
+                _ => continue,
+            }
+        };
+        for broker in brokers
+            .all()
+            .iter()
+            .take_while(|broker| broker.priority < active.priority)
+        {
+            if is_reachable(broker).await {
+                if fallbacks.send(broker.clone()).await.is_err() {
+                    return;
+                }
+                break;
+            }
+            debug!("Preferred MQTT broker {} is still unreachable", broker);
+        }
+    }
+}
+
+/// Whether the broker accepts TCP connections. The MQTT handshake is left to
+/// the event loop, which fails over again if it does not succeed.
+async fn is_reachable(broker: &Broker) -> bool {
+    let connect = TcpStream::connect((broker.host.as_str(), broker.port));
+    matches!(
+        tokio::time::timeout(PROBE_TIMEOUT, connect).await,
+        Ok(Ok(_))
+    )
+}
@@ -0,0 +1,175 @@
+use crate::failover::Broker;
+use crate::tls::LoadedCertificate;
+use crate::AppState;
+use actix_web::{web, HttpResponse, Responder};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+/// Whether actions may try to reach the broker.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Connected, or not yet failed often enough to give up on publishing.
+    #[default]
+    Closed,
+    /// The broker failed `mqtt.reconnect.circuit_threshold` times in a row:
+    /// actions fail until it is reachable again.
+    Open,
+    /// An open circuit's next connection attempt is under way.
+    HalfOpen,
+}
+
+/// MQTT connection status published by the event loop.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStatus {
+    /// The broker connected to, or being tried.
+    pub broker: Option<Broker>,
+    pub connected: bool,
+    pub connected_since: Option<SystemTime>,
+    pub last_connack: Option<SystemTime>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<SystemTime>,
+    /// Connection failures the event loop has retried after.
+    pub reconnect_count: u64,
+    pub circuit: CircuitState,
+    /// Failures since the last ConnAck.
+    pub consecutive_failures: u32,
+    /// When the event loop tries to connect again, while it backs off.
+    pub next_attempt_at: Option<SystemTime>,
+}
+
+impl ConnectionStatus {
+    pub fn on_connack(&mut self) {
+        let now = SystemTime::now();
+        self.connected = true;
+        self.connected_since = Some(now);
+        self.last_connack = Some(now);
+        self.circuit = CircuitState::Closed;
+        self.consecutive_failures = 0;
+        self.next_attempt_at = None;
+    }
+
+    /// Records the wait before the next attempt after a failure.
+    pub fn on_backoff(&mut self, failures: u32, delay: Duration, circuit_open: bool) {
+        self.consecutive_failures = failures;
+        self.next_attempt_at = Some(SystemTime::now() + delay);
+        if circuit_open {
+            self.circuit = CircuitState::Open;
+        }
+    }
+
+    /// The wait is over and the event loop tries to connect again.
+    pub fn on_attempt(&mut self) {
+        self.next_attempt_at = None;
+        if self.circuit == CircuitState::Open {
+            self.circuit = CircuitState::HalfOpen;
+        }
+    }
+
+    pub fn on_error(&mut self, error: String) {
+        self.connected = false;
+        self.connected_since = None;
+        self.last_error = Some(error);
+        self.last_error_at = Some(SystemTime::now());
+        self.reconnect_count += 1;
+    }
+}
+
+#[derive(Serialize)]
+struct ConnectionReport {
+    broker: Option<String>,
+    connected: bool,
+    connected_since: Option<String>,
+    last_connack: Option<String>,
+    last_error: Option<String>,
+    last_error_at: Option<String>,
+    reconnect_count: u64,
+    circuit: CircuitState,
+    consecutive_failures: u32,
+    next_attempt_at: Option<String>,
+}
+
+impl From<&ConnectionStatus> for ConnectionReport {
+    fn from(status: &ConnectionStatus) -> Self {
+        let format = |at: SystemTime| humantime::format_rfc3339_seconds(at).to_string();
+        ConnectionReport {
+            broker: status.broker.as_ref().map(Broker::to_string),
+            connected: status.connected,
+            connected_since: status.connected_since.map(format),
+            last_connack: status.last_connack.map(format),
+            last_error: status.last_error.clone(),
+            last_error_at: status.last_error_at.map(format),
+            reconnect_count: status.reconnect_count,
+            circuit: status.circuit,
+            consecutive_failures: status.consecutive_failures,
+            next_attempt_at: status.next_attempt_at.map(format),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CertificateReport {
+    subject: String,
+    expires_at: String,
+    days_until_expiry: i64,
+}
+
+impl From<&LoadedCertificate> for CertificateReport {
+    fn from(cert: &LoadedCertificate) -> Self {
+        CertificateReport {
+            subject: cert.subject.clone(),
+            expires_at: humantime::format_rfc3339_seconds(cert.not_after).to_string(),
+            days_until_expiry: cert.days_until_expiry(),
+        }
+    }
+}
+
+/// Liveness: the process is up and serving HTTP.
+pub async fn live() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "healthy"
+    }))
+}
+
+/// Readiness: the bridge can deliver messages, i.e. the broker is connected,
+/// and, if `mqtt.tls.unready_within_days` is set, the client certificate is
+/// not about to expire.
+pub async fn ready(data: web::Data<AppState>) -> impl Responder {
+    let status = data.connection.borrow().clone();
+    let report = ConnectionReport::from(&status);
+    let loaded = data.certificates.all();
+    let certificates: BTreeMap<_, _> = loaded
+        .iter()
+        .map(|(name, cert)| (*name, CertificateReport::from(cert)))
+        .collect();
+
+    let expiring = data.config.mqtt.tls.unready_within_days.and_then(|days| {
+        let client = loaded.get("client")?;
+        (client.days_until_expiry() < i64::from(days)).then_some(client)
+    });
+
+    if let Some(client) = expiring {
+        HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "status": "unavailable",
+            "reason": format!(
+                "client certificate expires in {} day(s)",
+                client.days_until_expiry()
+            ),
+            "mqtt": report,
+            "certificates": certificates
+        }))
+    } else if status.connected {
+        HttpResponse::Ok().json(serde_json::json!({
+            "status": "ready",
+            "mqtt": report,
+            "certificates": certificates
+        }))
+    } else {
+        HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "status": "unavailable",
+            "mqtt": report,
+            "certificates": certificates
+        }))
+    }
+}
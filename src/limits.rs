@@ -0,0 +1,154 @@
+use crate::config::{DeviceConfig, RateLimitConfig};
+use crate::error::ApiError;
+use actix_web::http::header::{HeaderValue, RETRY_AFTER};
+use actix_web::http::StatusCode;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Buckets kept before idle, fully refilled ones are dropped.
+const MAX_BUCKETS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Requests rejected since the last accepted one.
+    rejected: u64,
+}
+
+struct Cooldown {
+    last_actuation: Instant,
+    /// The actuation before, restored if the last one is cancelled.
+    previous: Option<Instant>,
+    /// Commands refused since the last one sent.
+    rejected: u64,
+}
+
+/// Per-client token buckets and per-device cooldowns guarding actions.
+pub struct Limits {
+    burst: f64,
+    /// Tokens added per second.
+    rate: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    cooldowns: Mutex<HashMap<String, Cooldown>>,
+}
+
+/// Why an action was refused, and when it may be retried.
+pub struct Limited {
+    pub retry_after: Duration,
+    /// Rejections in a row for the same client or device, this one included.
+    pub rejected: u64,
+}
+
+impl Limits {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Limits {
+            burst: f64::from(config.burst),
+            rate: f64::from(config.per_minute) / 60.0,
+            buckets: Mutex::new(HashMap::new()),
+            cooldowns: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.rate > 0.0
+    }
+
+    /// Takes a token from `client`'s bucket.
+    pub fn check_rate(&self, client: &str) -> Result<(), Limited> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= MAX_BUCKETS {
+            let (burst, rate) = (self.burst, self.rate);
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+            rejected: 0,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.rejected = 0;
+            return Ok(());
+        }
+        bucket.rejected += 1;
+        Err(Limited {
+            retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate),
+            rejected: bucket.rejected,
+        })
+    }
+
+    /// Records an actuation of `device` unless its cooldown is still running,
+    /// returning when it started.
+    pub fn start_cooldown(&self, device: &DeviceConfig) -> Result<Instant, Limited> {
+        let now = Instant::now();
+        if device.cooldown.is_zero() {
+            return Ok(now);
+        }
+        let mut cooldowns = self.cooldowns.lock().unwrap_or_else(|e| e.into_inner());
+        let previous = cooldowns
+            .get(&device.name)
+            .map(|cooldown| cooldown.last_actuation);
+        if let Some(cooldown) = cooldowns.get_mut(&device.name) {
+            let elapsed = now.duration_since(cooldown.last_actuation);
+            if elapsed < device.cooldown {
+                cooldown.rejected += 1;
+                return Err(Limited {
+                    retry_after: device.cooldown - elapsed,
+                    rejected: cooldown.rejected,
+                });
+            }
+        }
+        cooldowns.insert(
+            device.name.clone(),
+            Cooldown {
+                last_actuation: now,
+                previous,
+                rejected: 0,
+            },
+        );
+        Ok(now)
+    }
+
+    /// Undoes the actuation `start_cooldown` recorded at `started`, for a
+    /// command that was never sent, unless another one was recorded since.
+    pub fn cancel_cooldown(&self, device: &DeviceConfig, started: Instant) {
+        let mut cooldowns = self.cooldowns.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(cooldown) = cooldowns.get_mut(&device.name) else {
+            return;
+        };
+        if cooldown.last_actuation != started {
+            return;
+        }
+        match cooldown.previous.take() {
+            Some(previous) => cooldown.last_actuation = previous,
+            None => {
+                cooldowns.remove(&device.name);
+            }
+        }
+    }
+}
+
+impl Limited {
+    /// Whole seconds to wait, rounded up as `Retry-After` requires.
+    pub fn retry_after_secs(&self) -> u64 {
+        self.retry_after.as_secs() + u64::from(self.retry_after.subsec_nanos() > 0)
+    }
+
+    pub fn into_error(self, message: String) -> ApiError {
+        let seconds = self.retry_after_secs();
+        ApiError::new(StatusCode::TOO_MANY_REQUESTS, message)
+            .with("retry_after", seconds)
+            .with_header(RETRY_AFTER, HeaderValue::from(seconds))
+    }
+}
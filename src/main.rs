@@ -1,163 +1,260 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
-use log::{error, info};
-use rumqttc::{AsyncClient, MqttOptions, QoS, Transport};
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
-use rustls::ClientConfig;
-use rustls_pemfile::{certs, private_key};
-use std::fs::File;
-use std::io::BufReader;
+mod audit;
+mod auth;
+mod config;
+mod error;
+mod failover;
+mod health;
+mod limits;
+mod metrics;
+mod mqtt;
+mod reconnect;
+mod routes;
+mod state;
+mod tls;
+
+use actix_web::{middleware, web, App, HttpServer};
+use audit::AuditLog;
+use auth::ApiKeys;
+use config::{Config, StartupMode, TlsMode};
+use failover::Brokers;
+use health::ConnectionStatus;
+use limits::Limits;
+use log::{error, info, warn};
+use metrics::Metrics;
+use mqtt::MqttClient;
+use state::DeviceStates;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tls::{Certificates, ServerCertificate};
+use tokio::sync::{mpsc, watch};
 
 struct AppState {
-    mqtt_client: Arc<Mutex<AsyncClient>>,
+    mqtt_client: MqttClient,
+    config: Arc<Config>,
+    states: Arc<DeviceStates>,
+    connection: Arc<watch::Sender<ConnectionStatus>>,
+    metrics: Arc<Metrics>,
+    api_keys: ApiKeys,
+    limits: Limits,
+    audit: AuditLog,
+    certificates: Arc<Certificates>,
 }
 
-async fn trigger_garage(data: web::Data<AppState>) -> impl Responder {
-    info!("Received garage door trigger request");
-
-    // Topic should be configured via environment variable in production
-    let topic = std::env::var("MQTT_TOPIC").unwrap_or_else(|_| "garage/trigger".to_string());
-    let payload = std::env::var("MQTT_PAYLOAD").unwrap_or_else(|_| "1".to_string());
-
-    let client = data.mqtt_client.lock().await;
-    match client.publish(
-        &topic,
-        QoS::AtLeastOnce,
-        false,
-        payload.as_bytes(),
-    ).await {
-        Ok(_) => {
-            info!("Successfully published MQTT message");
-            HttpResponse::Ok().json(serde_json::json!({
-                "status": "success",
-                "message": "Garage door triggered"
-            }))
-        }
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+
+    // Load configuration file and environment overrides
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
         Err(e) => {
-            error!("Failed to publish MQTT message: {}", e);
-            HttpResponse::InternalServerError().json(serde_json::json!({
-                "status": "error",
-                "message": format!("Failed to trigger garage door: {}", e)
-            }))
+            error!("Invalid configuration: {}", e);
+            std::process::exit(2);
         }
-    }
-}
-
-async fn health_check() -> impl Responder {
-    HttpResponse::Ok().json(serde_json::json!({
-        "status": "healthy"
-    }))
-}
+    };
 
-fn load_tls_config(
-    ca_path: &str,
-    cert_path: &str,
-    key_path: &str,
-) -> Result<ClientConfig, Box<dyn std::error::Error>> {
-    // Load CA certificate
-    let ca_file = File::open(ca_path)?;
-    let mut ca_reader = BufReader::new(ca_file);
-    let ca_certs: Vec<CertificateDer<'static>> = certs(&mut ca_reader)
-        .collect::<Result<Vec<_>, _>>()?;
-
-    let mut root_store = rustls::RootCertStore::empty();
-    for cert in ca_certs {
-        root_store.add(cert)?;
+    info!("Initializing MQTT client...");
+    let brokers = Brokers::new(&config.mqtt);
+    for broker in brokers.all() {
+        info!("MQTT Broker: {} (priority {})", broker, broker.priority);
+    }
+    for device in &config.devices {
+        info!("Device '{}' publishes to '{}'", device.name, device.topic);
     }
 
-    // Load client certificate
-    let cert_file = File::open(cert_path)?;
-    let mut cert_reader = BufReader::new(cert_file);
-    let client_certs: Vec<CertificateDer<'static>> = certs(&mut cert_reader)
-        .collect::<Result<Vec<_>, _>>()?;
-
-    // Load client private key
-    let key_file = File::open(key_path)?;
-    let mut key_reader = BufReader::new(key_file);
-    let client_key: PrivateKeyDer<'static> = private_key(&mut key_reader)?
-        .ok_or("No private key found in file")?;
+    // Load TLS configuration
+    let tls = &config.mqtt.tls;
+    let transport = match tls::transport(tls) {
+        Ok(transport) => transport,
+        Err(e) => {
+            error!("Failed to load TLS certificates: {}", e);
+            std::process::exit(2);
+        }
+    };
+    if tls.mode == TlsMode::Disabled {
+        warn!("MQTT TLS is disabled: traffic to the broker is not encrypted");
+        if config.mqtt.password.is_some() {
+            warn!("The MQTT password is sent in plaintext");
+        }
+    }
 
-    // Build TLS config with client authentication
-    let config = ClientConfig::builder()
-        .with_root_certificates(root_store)
-        .with_client_auth_cert(client_certs, client_key)?;
+    // Create MQTT client; renewed certificates are sent to its event loop
+    let (client, eventloop) = mqtt::connect(&config.mqtt, brokers.active(), transport.clone());
+    let (transports, renewed_transport) = watch::channel(transport);
 
-    Ok(config)
-}
+    let api_keys = ApiKeys::new(&config.auth);
+    if api_keys.is_enabled() {
+        if !config.auth.keys.is_empty() {
+            info!("API key authentication enabled with {} key(s)", config.auth.keys.len());
+        }
+        if !config.auth.clients.is_empty() {
+            info!(
+                "Client certificate authentication enabled for {} client(s)",
+                config.auth.clients.len()
+            );
+        }
+    } else {
+        warn!("No API keys configured: requests are not authenticated by the bridge");
+    }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    let limits = Limits::new(&config.rate_limit);
+    if limits.is_enabled() {
+        info!(
+            "Rate limiting device actions to {} per minute, bursts of {}",
+            config.rate_limit.per_minute, config.rate_limit.burst
+        );
+    }
 
-    // Read configuration from environment variables
-    let mqtt_host = std::env::var("MQTT_HOST").unwrap_or_else(|_| "mqtt.example.com".to_string());
-    let mqtt_port: u16 = std::env::var("MQTT_PORT")
-        .unwrap_or_else(|_| "8883".to_string())
-        .parse()
-        .expect("Invalid MQTT_PORT");
-    let ca_path = std::env::var("CA_CERT_PATH").unwrap_or_else(|_| "/certs/ca.crt".to_string());
-    let cert_path = std::env::var("CLIENT_CERT_PATH").unwrap_or_else(|_| "/certs/client.crt".to_string());
-    let key_path = std::env::var("CLIENT_KEY_PATH").unwrap_or_else(|_| "/certs/client.key".to_string());
-    let http_port: u16 = std::env::var("HTTP_PORT")
-        .unwrap_or_else(|_| "8080".to_string())
-        .parse()
-        .expect("Invalid HTTP_PORT");
+    match &config.audit.path {
+        Some(path) => info!("Recording device actions to audit log {}", path.display()),
+        None => warn!("No audit.path configured: device actions are not audited"),
+    }
+    let audit = AuditLog::new(config.audit.path.clone());
 
-    info!("Initializing MQTT client...");
-    info!("MQTT Broker: {}:{}", mqtt_host, mqtt_port);
+    // Load the HTTPS certificate, if the HTTP listener is to speak TLS
+    let https = if config.http.tls.is_enabled() {
+        let loaded = ServerCertificate::load(&config.http.tls).and_then(|certificate| {
+            let certificate = Arc::new(certificate);
+            let server_config =
+                certificate.server_config(&config.http.tls, api_keys.clients())?;
+            Ok((certificate, server_config))
+        });
+        match loaded {
+            Ok(https) => Some(https),
+            Err(e) => {
+                error!("Failed to load HTTPS certificate: {}", e);
+                std::process::exit(2);
+            }
+        }
+    } else {
+        None
+    };
 
-    // Set up MQTT options
-    let mut mqtt_options = MqttOptions::new("garage-mqtt-bridge", mqtt_host, mqtt_port);
-    mqtt_options.set_keep_alive(Duration::from_secs(30));
+    let metrics = Arc::new(Metrics::new());
+    let certificates = Arc::new(Certificates::default());
+    certificates.load(tls, &metrics);
 
-    // Load TLS configuration
-    let tls_config = load_tls_config(&ca_path, &cert_path, &key_path)
-        .expect("Failed to load TLS certificates");
+    // Create application state
+    let bind_addr = (config.http.bind.clone(), config.http.port);
+    let app_state = web::Data::new(AppState {
+        mqtt_client: client.clone(),
+        states: Arc::new(DeviceStates::new(&config)),
+        connection: Arc::new(watch::channel(ConnectionStatus::default()).0),
+        metrics,
+        api_keys,
+        limits,
+        audit,
+        certificates,
+        config,
+    });
 
-    mqtt_options.set_transport(Transport::tls_with_config(
-        rumqttc::TlsConfiguration::Rustls(Arc::new(tls_config)),
+    // Spawn tasks to handle the MQTT connection, fall back to preferred
+    // brokers and reload its certificates
+    let (fallback, fallbacks) = mpsc::channel(1);
+    tokio::spawn(mqtt::run_event_loop(
+        eventloop,
+        client,
+        app_state.clone(),
+        renewed_transport,
+        brokers.clone(),
+        fallbacks,
+    ));
+    tokio::spawn(failover::watch_preferred(
+        brokers,
+        app_state.config.mqtt.fallback_interval,
+        app_state.connection.subscribe(),
+        fallback,
+    ));
+    tokio::spawn(tls::watch_certificates(
+        app_state.config.mqtt.tls.clone(),
+        app_state.certificates.clone(),
+        app_state.metrics.clone(),
+        transports,
+    ));
+    tokio::spawn(tls::monitor_expiry(
+        app_state.certificates.clone(),
+        app_state.config.mqtt.tls.expiry_warning_days.clone(),
     ));
 
-    // Create MQTT client
-    let (client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
-    let client = Arc::new(Mutex::new(client));
-
-    // Spawn a task to handle the MQTT connection
-    tokio::spawn(async move {
-        info!("Starting MQTT event loop...");
-        loop {
-            match eventloop.poll().await {
-                Ok(notification) => {
-                    info!("MQTT notification: {:?}", notification);
-                }
-                Err(e) => {
-                    error!("MQTT connection error: {}. Retrying...", e);
-                    tokio::time::sleep(Duration::from_secs(5)).await;
-                }
+    // Wait for the first ConnAck before serving
+    let mqtt = &app_state.config.mqtt;
+    let timeout = humantime::format_duration(mqtt.startup_timeout);
+    info!("Waiting up to {} for the MQTT broker to connect...", timeout);
+    let started = Instant::now();
+    let mut connection = app_state.connection.subscribe();
+    let connected = tokio::time::timeout(
+        mqtt.startup_timeout,
+        connection.wait_for(|status| status.connected),
+    )
+    .await
+    .is_ok();
+    if connected {
+        let elapsed = Duration::from_millis(started.elapsed().as_millis() as u64);
+        info!(
+            "MQTT broker connected after {}",
+            humantime::format_duration(elapsed)
+        );
+    } else {
+        let reason = app_state
+            .connection
+            .borrow()
+            .last_error
+            .clone()
+            .unwrap_or_else(|| "no ConnAck received".to_string());
+        match mqtt.startup_mode {
+            StartupMode::Strict => {
+                error!(
+                    "MQTT broker not connected within {} ({}); exiting as \
+                     mqtt.startup_mode is strict",
+                    timeout, reason
+                );
+                std::process::exit(1);
             }
+            StartupMode::Lenient => warn!(
+                "MQTT broker not connected within {} ({}); serving HTTP degraded, \
+                 not ready until it connects",
+                timeout, reason
+            ),
         }
-    });
-
-    // Allow MQTT connection to establish
-    tokio::time::sleep(Duration::from_secs(2)).await;
-
-    info!("Starting HTTP server on 0.0.0.0:{}...", http_port);
+    }
 
-    // Create application state
-    let app_state = web::Data::new(AppState {
-        mqtt_client: client,
+    let http = app_state.config.http.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(app_state.clone())
+            .wrap(middleware::from_fn(auth::authenticate))
+            .wrap(middleware::from_fn(metrics::track_requests))
+            .configure(routes::configure)
     });
 
     // Start HTTP server
-    HttpServer::new(move || {
-        App::new()
-            .app_data(app_state.clone())
-            .route("/garage", web::post().to(trigger_garage))
-            .route("/health", web::get().to(health_check))
-    })
-    .bind(("0.0.0.0", http_port))?
-    .run()
-    .await
+    let server = match https {
+        Some((certificate, server_config)) => {
+            info!("Starting HTTPS server on {}:{}...", http.bind, http.port);
+            tokio::spawn(tls::watch_server_certificate(http.tls.clone(), certificate));
+            server
+                .on_connect(auth::record_peer_certificate)
+                .bind_rustls_0_23(bind_addr, server_config)?
+        }
+        None => {
+            info!("Starting HTTP server on {}:{}...", http.bind, http.port);
+            server.bind(bind_addr)?
+        }
+    };
+
+    if let Some(redirect_port) = http.tls.redirect_port {
+        info!("Redirecting HTTP on {}:{} to HTTPS", http.bind, redirect_port);
+        let https_port = http.port;
+        let redirect = HttpServer::new(move || {
+            App::new().default_service(web::to(move |req| {
+                routes::redirect_to_https(req, https_port)
+            }))
+        })
+        .workers(1)
+        .bind((http.bind.clone(), redirect_port))?
+        .run();
+        actix_web::rt::spawn(redirect);
+    }
+
+    server.run().await
 }
@@ -0,0 +1,211 @@
+use crate::failover::Brokers;
+use crate::AppState;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use log::error;
+use prometheus::{
+    Encoder, GaugeVec, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    Opts, Registry, TextEncoder,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Prometheus metrics exposed at `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    pub http_requests: IntCounterVec,
+    pub rate_limited: IntCounterVec,
+    pub mqtt_publishes: IntCounterVec,
+    pub puback_latency: Histogram,
+    pub mqtt_reconnects: IntCounter,
+    pub mqtt_connected: IntGauge,
+    pub mqtt_circuit_open: IntGauge,
+    pub mqtt_broker_switches: IntCounterVec,
+    pub mqtt_active_broker: IntGaugeVec,
+    pub certificate_expiry: GaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("garage_bridge".to_string()), None)
+            .expect("valid metrics prefix");
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by route and status"),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+        let rate_limited = IntCounterVec::new(
+            Opts::new(
+                "http_rate_limited_total",
+                "Device actions refused by rate limits or cooldowns",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let mqtt_publishes = IntCounterVec::new(
+            Opts::new(
+                "mqtt_publishes_total",
+                "MQTT publishes by topic and outcome",
+            ),
+            &["topic", "outcome"],
+        )
+        .unwrap();
+        let puback_latency = Histogram::with_opts(
+            HistogramOpts::new(
+                "mqtt_ack_latency_seconds",
+                "Time from sending a QoS 1/2 publish to its PubAck/PubComp",
+            )
+            .buckets(vec![
+                0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+            ]),
+        )
+        .unwrap();
+        let mqtt_reconnects = IntCounter::new(
+            "mqtt_reconnect_attempts_total",
+            "MQTT connection failures the event loop retried after",
+        )
+        .unwrap();
+        let mqtt_connected = IntGauge::new(
+            "mqtt_connected",
+            "1 while connected to the MQTT broker, 0 otherwise",
+        )
+        .unwrap();
+        let mqtt_circuit_open = IntGauge::new(
+            "mqtt_circuit_open",
+            "1 while actions fail fast because the MQTT broker keeps failing",
+        )
+        .unwrap();
+        let mqtt_broker_switches = IntCounterVec::new(
+            Opts::new(
+                "mqtt_broker_switches_total",
+                "Switches between MQTT brokers by reason",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let mqtt_active_broker = IntGaugeVec::new(
+            Opts::new(
+                "mqtt_active_broker",
+                "1 for the MQTT broker connected to or being tried, 0 for the others",
+            ),
+            &["broker"],
+        )
+        .unwrap();
+        let certificate_expiry = GaugeVec::new(
+            Opts::new(
+                "tls_certificate_expiry_timestamp_seconds",
+                "Expiry of the loaded TLS certificates as a Unix timestamp",
+            ),
+            &["certificate"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(rate_limited.clone())).unwrap();
+        registry.register(Box::new(mqtt_publishes.clone())).unwrap();
+        registry.register(Box::new(puback_latency.clone())).unwrap();
+        registry
+            .register(Box::new(mqtt_reconnects.clone()))
+            .unwrap();
+        registry.register(Box::new(mqtt_connected.clone())).unwrap();
+        registry
+            .register(Box::new(mqtt_circuit_open.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(mqtt_broker_switches.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(mqtt_active_broker.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(certificate_expiry.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            http_requests,
+            rate_limited,
+            mqtt_publishes,
+            puback_latency,
+            mqtt_reconnects,
+            mqtt_connected,
+            mqtt_circuit_open,
+            mqtt_broker_switches,
+            mqtt_active_broker,
+            certificate_expiry,
+        }
+    }
+
+    /// Marks the active broker in `mqtt_active_broker`.
+    pub fn record_active_broker(&self, brokers: &Brokers) {
+        for broker in brokers.all() {
+            let active = i64::from(broker == brokers.active());
+            self.mqtt_active_broker
+                .with_label_values(&[&broker.to_string()])
+                .set(active);
+        }
+    }
+
+    /// Counts a switch to another broker, now the active one.
+    pub fn record_broker_switch(&self, reason: &str, brokers: &Brokers) {
+        self.mqtt_broker_switches.with_label_values(&[reason]).inc();
+        self.record_active_broker(brokers);
+    }
+
+    /// Sets the expiry gauge of a loaded certificate.
+    pub fn record_certificate_expiry(&self, name: &str, expiry: SystemTime) {
+        let seconds = expiry
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64());
+        self.certificate_expiry
+            .with_label_values(&[name])
+            .set(seconds);
+    }
+
+    fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+/// Middleware counting requests by matched route pattern, method and status.
+pub async fn track_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let data = req.app_data::<web::Data<AppState>>().cloned();
+    let method = req.method().to_string();
+    let result = next.call(req).await;
+
+    if let Some(data) = data {
+        let (route, status) = match &result {
+            Ok(res) => (
+                res.request()
+                    .match_pattern()
+                    .unwrap_or_else(|| "unmatched".to_string()),
+                res.status(),
+            ),
+            Err(e) => ("unmatched".to_string(), e.as_response_error().status_code()),
+        };
+        data.metrics
+            .http_requests
+            .with_label_values(&[&route, &method, status.as_str()])
+            .inc();
+    }
+    result
+}
+
+pub async fn render(data: web::Data<AppState>) -> HttpResponse {
+    match data.metrics.render() {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(TextEncoder::new().format_type())
+            .body(body),
+        Err(e) => {
+            error!("Failed to encode metrics: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -0,0 +1,750 @@
+use crate::config::{ClientIdSuffix, DeviceConfig, MqttConfig, Protocol, Qos};
+use crate::failover::{Broker, Brokers};
+use crate::health::ConnectionStatus;
+use crate::reconnect::Backoff;
+use crate::state::{DeviceStates, DoorState};
+use crate::AppState;
+use actix_web::web;
+use actix_web::web::Bytes;
+use log::{debug, error, info, warn};
+use ring::rand::{SecureRandom, SystemRandom};
+use rumqttc::v5::mqttbytes::v5::{
+    Packet as PacketV5, PubAckReason, PubRecReason, PublishProperties,
+};
+use rumqttc::{v5, Event, Outgoing, Packet, PubAck, PubComp, QoS, Transport};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{mpsc, oneshot, watch};
+
+/// Capacity of the request channel between clients and the event loop.
+const REQUEST_CAPACITY: usize = 10;
+
+/// Length of the random correlation data identifying a request.
+const CORRELATION_LEN: usize = 16;
+
+/// Retained on the availability topic while the bridge is connected.
+const ONLINE: &str = "online";
+/// Published on the availability topic by the broker, as the bridge's Last
+/// Will, when the connection is lost without a clean disconnect.
+const OFFLINE: &str = "offline";
+
+/// Client handle for the protocol version configured in `mqtt.protocol`.
+#[derive(Clone)]
+pub struct MqttClient {
+    handle: Handle,
+    /// Publishes handed to the event loop but not yet written to the
+    /// network, in the order they were queued. The event loop learns packet
+    /// ids only when writing, so this order is what matches a publish to
+    /// its `Outgoing::Publish` event and then its acknowledgement.
+    queued: Arc<Mutex<VecDeque<oneshot::Sender<Delivery>>>>,
+    /// MQTT 5: topic replies are requested on, from `mqtt.response_topic`.
+    response_topic: Option<String>,
+    /// Topic of the birth message, from `mqtt.availability_topic`.
+    availability_topic: Option<String>,
+    replies: Replies,
+}
+
+/// Requests awaiting a reply, by correlation data.
+type Replies = Arc<Mutex<HashMap<Vec<u8>, oneshot::Sender<Reply>>>>;
+
+#[derive(Clone)]
+enum Handle {
+    V4(rumqttc::AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+/// How the broker answered a publish: `Ok` once it was acknowledged (or,
+/// for QoS 0, written), `Err` with the reason code if MQTT 5 refused it.
+pub type Delivery = Result<(), String>;
+
+/// A device's reply to a request.
+#[derive(Debug)]
+pub struct Reply {
+    pub payload: Bytes,
+    /// MQTT 5 content type of the reply, if the device set one.
+    pub content_type: Option<String>,
+}
+
+/// A queued publish.
+pub struct Published {
+    /// Resolves once the broker acknowledged the publish.
+    pub delivery: oneshot::Receiver<Delivery>,
+    /// Set when the device was asked to reply.
+    pub reply: Option<PendingReply>,
+}
+
+/// A reply the event loop will hand over when it arrives. Dropping it stops
+/// waiting: a reply arriving later is ignored.
+pub struct PendingReply {
+    correlation: Vec<u8>,
+    receiver: oneshot::Receiver<Reply>,
+    replies: Replies,
+}
+
+/// Event loop matching an [`MqttClient`].
+pub enum EventLoop {
+    V4(Box<rumqttc::EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+/// A request the client could not hand to the event loop. Only the message
+/// is kept: the rejected request itself is large and of no further use.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct ClientError(String);
+
+impl From<rumqttc::ClientError> for ClientError {
+    fn from(e: rumqttc::ClientError) -> Self {
+        ClientError(e.to_string())
+    }
+}
+
+impl From<v5::ClientError> for ClientError {
+    fn from(e: v5::ClientError) -> Self {
+        ClientError(e.to_string())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectionError {
+    #[error(transparent)]
+    V4(#[from] rumqttc::ConnectionError),
+    #[error(transparent)]
+    V5(#[from] v5::ConnectionError),
+}
+
+/// The events the bridge reacts to, independent of the protocol version.
+enum Notification {
+    ConnAck(String),
+    Publish {
+        topic: String,
+        payload: Bytes,
+        /// MQTT 5 correlation data and content type, if set.
+        correlation: Option<Bytes>,
+        content_type: Option<String>,
+    },
+    /// A publish was written to the network; packet id 0 for QoS 0.
+    Sent(u16),
+    /// The broker acknowledged the publish with this packet id.
+    Acked(u16, Delivery),
+    Other(String),
+}
+
+/// Why the event loop drops a working connection.
+enum Restart {
+    /// The TLS certificates were renewed.
+    Renewed,
+    /// A broker preferred over the active one is reachable again.
+    Fallback(Broker),
+}
+
+/// Creates a client and event loop for `broker` and the configured protocol.
+pub fn connect(
+    config: &MqttConfig,
+    broker: &Broker,
+    transport: Transport,
+) -> (MqttClient, EventLoop) {
+    let client_id = client_id(config);
+    info!("MQTT client id: {}", client_id);
+    match config.protocol {
+        Protocol::V311 => {
+            let options = v4_options(config, client_id, broker, transport);
+            let (client, eventloop) = rumqttc::AsyncClient::new(options, REQUEST_CAPACITY);
+            (
+                MqttClient::new(Handle::V4(client), config),
+                EventLoop::V4(Box::new(eventloop)),
+            )
+        }
+        Protocol::V5 => {
+            let options = v5_options(config, client_id, broker, transport);
+            let (client, eventloop) = v5::AsyncClient::new(options, REQUEST_CAPACITY);
+            (
+                MqttClient::new(Handle::V5(client), config),
+                EventLoop::V5(Box::new(eventloop)),
+            )
+        }
+    }
+}
+
+fn v4_options(
+    config: &MqttConfig,
+    client_id: String,
+    broker: &Broker,
+    transport: Transport,
+) -> rumqttc::MqttOptions {
+    let mut options = rumqttc::MqttOptions::new(client_id, broker.host.clone(), broker.port);
+    options.set_keep_alive(config.keep_alive);
+    options.set_transport(transport);
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+    }
+    if let Some(topic) = &config.availability_topic {
+        options.set_last_will(rumqttc::LastWill::new(
+            topic,
+            OFFLINE,
+            QoS::AtLeastOnce,
+            true,
+        ));
+    }
+    options
+}
+
+fn v5_options(
+    config: &MqttConfig,
+    client_id: String,
+    broker: &Broker,
+    transport: Transport,
+) -> v5::MqttOptions {
+    let mut options = v5::MqttOptions::new(client_id, broker.host.clone(), broker.port);
+    options.set_keep_alive(config.keep_alive);
+    options.set_transport(transport);
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+    }
+    if let Some(topic) = &config.availability_topic {
+        options.set_last_will(v5::mqttbytes::v5::LastWill::new(
+            topic,
+            OFFLINE,
+            v5::mqttbytes::QoS::AtLeastOnce,
+            true,
+            None,
+        ));
+    }
+    options
+}
+
+/// `mqtt.client_id` with its configured suffix.
+fn client_id(config: &MqttConfig) -> String {
+    let suffix = match config.client_id_suffix {
+        ClientIdSuffix::None => return config.client_id.clone(),
+        ClientIdSuffix::Hostname => hostname().unwrap_or_else(|| {
+            warn!("Cannot determine the host name; using a random client id suffix");
+            random_suffix()
+        }),
+        ClientIdSuffix::Random => random_suffix(),
+    };
+    format!("{}-{}", config.client_id, suffix)
+}
+
+/// `HOSTNAME` as set by shells and Kubernetes, or the kernel's host name.
+fn hostname() -> Option<String> {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+fn random_suffix() -> String {
+    let mut bytes = [0; 4];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        // Not the process id: every container may run as pid 1.
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        bytes = nanos.to_be_bytes();
+    }
+    hex::encode(bytes)
+}
+
+impl MqttClient {
+    fn new(handle: Handle, config: &MqttConfig) -> Self {
+        MqttClient {
+            handle,
+            queued: Arc::new(Mutex::new(VecDeque::new())),
+            // Only MQTT 5 can carry a response topic; validation ensures it
+            // is unset otherwise.
+            response_topic: config.response_topic.clone(),
+            availability_topic: config.availability_topic.clone(),
+            replies: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Queues `payload` for the device's topic with its QoS, retain flag
+    /// and, over MQTT 5, its publish properties. Devices with a
+    /// `response_timeout` are asked to reply on the response topic.
+    ///
+    /// Fails instead of waiting when the request channel is full, which
+    /// only happens while the event loop cannot keep up or is reconnecting.
+    pub fn publish(&self, device: &DeviceConfig, payload: &str) -> Result<Published, ClientError> {
+        let payload = payload.as_bytes().to_vec();
+        // Registered before publishing: the reply may arrive before the
+        // acknowledgement.
+        let reply = match (&self.response_topic, device.response_timeout) {
+            (Some(_), Some(_)) => Some(self.expect_reply()?),
+            _ => None,
+        };
+        let mut properties = publish_properties(device);
+        if let Some(reply) = &reply {
+            properties.response_topic = self.response_topic.clone();
+            properties.correlation_data = Some(Bytes::from(reply.correlation.clone()));
+        }
+        let delivery = self.queue(
+            &device.topic,
+            device.qos,
+            device.retain,
+            payload,
+            properties,
+        )?;
+        Ok(Published { delivery, reply })
+    }
+
+    /// Publishes the retained birth message, if an availability topic is
+    /// configured.
+    fn announce_online(&self) -> Result<(), ClientError> {
+        if let Some(topic) = &self.availability_topic {
+            // Nobody waits for the delivery; its sender only keeps the
+            // queue aligned with the publishes written.
+            self.queue(
+                topic,
+                Qos::AtLeastOnce,
+                true,
+                ONLINE.into(),
+                PublishProperties::default(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Hands a publish to the event loop and queues its delivery sender.
+    /// `properties` are dropped on MQTT 3.1.1.
+    fn queue(
+        &self,
+        topic: &str,
+        qos: Qos,
+        retain: bool,
+        payload: Vec<u8>,
+        properties: PublishProperties,
+    ) -> Result<oneshot::Receiver<Delivery>, ClientError> {
+        // Held while queueing so concurrent publishes enter the request
+        // channel in the same order as their delivery senders.
+        let mut queued = self.queued.lock().unwrap_or_else(|e| e.into_inner());
+        match &self.handle {
+            Handle::V4(client) => client.try_publish(topic, qos.into(), retain, payload)?,
+            Handle::V5(client) => client.try_publish_with_properties(
+                topic,
+                qos.into(),
+                retain,
+                payload,
+                properties,
+            )?,
+        }
+        let (sender, receiver) = oneshot::channel();
+        queued.push_back(sender);
+        Ok(receiver)
+    }
+
+    /// Registers a request under fresh random correlation data.
+    fn expect_reply(&self) -> Result<PendingReply, ClientError> {
+        let mut correlation = vec![0; CORRELATION_LEN];
+        SystemRandom::new()
+            .fill(&mut correlation)
+            .map_err(|_| ClientError("cannot generate correlation data".to_string()))?;
+        let (sender, receiver) = oneshot::channel();
+        self.replies
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(correlation.clone(), sender);
+        Ok(PendingReply {
+            correlation,
+            receiver,
+            replies: self.replies.clone(),
+        })
+    }
+
+    /// Hands a reply to the request it correlates to. Returns false if no
+    /// request is waiting for it any more.
+    fn resolve_reply(&self, correlation: &[u8], reply: Reply) -> bool {
+        let sender = self
+            .replies
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(correlation);
+        sender.is_some_and(|sender| sender.send(reply).is_ok())
+    }
+
+    /// Takes the delivery sender of the oldest publish not yet written.
+    fn next_queued(&self) -> Option<oneshot::Sender<Delivery>> {
+        self.queued
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop_front()
+    }
+
+    /// Subscribes with QoS 1 without waiting for room in the request channel.
+    fn try_subscribe(&self, topic: &str) -> Result<(), ClientError> {
+        match &self.handle {
+            Handle::V4(client) => client.try_subscribe(topic, QoS::AtLeastOnce)?,
+            Handle::V5(client) => client.try_subscribe(topic, v5::mqttbytes::QoS::AtLeastOnce)?,
+        }
+        Ok(())
+    }
+}
+
+impl PendingReply {
+    /// Waits up to `timeout` for the reply.
+    pub async fn wait(mut self, timeout: Duration) -> Option<Reply> {
+        tokio::time::timeout(timeout, &mut self.receiver)
+            .await
+            .ok()?
+            .ok()
+    }
+}
+
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        self.replies
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.correlation);
+    }
+}
+
+fn publish_properties(device: &DeviceConfig) -> PublishProperties {
+    PublishProperties {
+        message_expiry_interval: device.message_expiry.map(|expiry| expiry.as_secs() as u32),
+        content_type: device.content_type.clone(),
+        user_properties: device
+            .user_properties
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect(),
+        ..PublishProperties::default()
+    }
+}
+
+impl EventLoop {
+    /// Moves requests still in the request channel into the session, which
+    /// the next clean-session connect discards.
+    fn clean(&mut self) {
+        match self {
+            EventLoop::V4(eventloop) => eventloop.clean(),
+            EventLoop::V5(eventloop) => eventloop.clean(),
+        }
+    }
+
+    /// Drops the connection, so the next poll reconnects over `transport`.
+    /// Requests not yet written are discarded along with the session.
+    fn reconnect_with(&mut self, transport: Transport) {
+        match self {
+            EventLoop::V4(eventloop) => {
+                eventloop.mqtt_options.set_transport(transport);
+                eventloop.clean();
+            }
+            EventLoop::V5(eventloop) => {
+                eventloop.options.set_transport(transport);
+                eventloop.clean();
+            }
+        }
+    }
+
+    /// Drops the connection, if any, so the next poll connects to `broker`
+    /// with otherwise unchanged options.
+    fn connect_to(&mut self, config: &MqttConfig, broker: &Broker) {
+        match self {
+            EventLoop::V4(eventloop) => {
+                let options = &eventloop.mqtt_options;
+                eventloop.mqtt_options =
+                    v4_options(config, options.client_id(), broker, options.transport());
+                eventloop.clean();
+            }
+            EventLoop::V5(eventloop) => {
+                let options = &eventloop.options;
+                eventloop.options =
+                    v5_options(config, options.client_id(), broker, options.transport());
+                eventloop.clean();
+            }
+        }
+    }
+
+    async fn poll(&mut self) -> Result<Notification, ConnectionError> {
+        Ok(match self {
+            EventLoop::V4(eventloop) => match eventloop.poll().await? {
+                Event::Incoming(Packet::ConnAck(connack)) => {
+                    Notification::ConnAck(format!("{:?}", connack.code))
+                }
+                Event::Incoming(Packet::Publish(publish)) => Notification::Publish {
+                    topic: publish.topic,
+                    payload: publish.payload,
+                    correlation: None,
+                    content_type: None,
+                },
+                Event::Outgoing(Outgoing::Publish(pkid)) => Notification::Sent(pkid),
+                Event::Incoming(Packet::PubAck(PubAck { pkid }))
+                | Event::Incoming(Packet::PubComp(PubComp { pkid })) => {
+                    Notification::Acked(pkid, Ok(()))
+                }
+                other => Notification::Other(format!("{:?}", other)),
+            },
+            EventLoop::V5(eventloop) => match eventloop.poll().await? {
+                v5::Event::Incoming(PacketV5::ConnAck(connack)) => {
+                    Notification::ConnAck(format!("{:?}", connack.code))
+                }
+                v5::Event::Incoming(PacketV5::Publish(publish)) => {
+                    let properties = publish.properties.unwrap_or_default();
+                    Notification::Publish {
+                        topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+                        payload: publish.payload,
+                        correlation: properties.correlation_data,
+                        content_type: properties.content_type,
+                    }
+                }
+                v5::Event::Outgoing(Outgoing::Publish(pkid)) => Notification::Sent(pkid),
+                v5::Event::Incoming(PacketV5::PubAck(ack)) => Notification::Acked(
+                    ack.pkid,
+                    match ack.reason {
+                        PubAckReason::Success | PubAckReason::NoMatchingSubscribers => Ok(()),
+                        reason => Err(format!("{:?}", reason)),
+                    },
+                ),
+                // A refusal ends a QoS 2 flow at PubRec; success continues
+                // to PubComp.
+                v5::Event::Incoming(PacketV5::PubRec(rec))
+                    if !matches!(
+                        rec.reason,
+                        PubRecReason::Success | PubRecReason::NoMatchingSubscribers
+                    ) =>
+                {
+                    Notification::Acked(rec.pkid, Err(format!("{:?}", rec.reason)))
+                }
+                v5::Event::Incoming(PacketV5::PubComp(comp)) => {
+                    Notification::Acked(comp.pkid, Ok(()))
+                }
+                other => Notification::Other(format!("{:?}", other)),
+            },
+        })
+    }
+}
+
+/// Drives the MQTT connection: reconnects on errors and with renewed TLS
+/// certificates, announces the bridge and (re)subscribes to state and
+/// response topics after every ConnAck, feeds incoming state messages into
+/// the device states, resolves publish deliveries and replies, and publishes
+/// connection status and metrics.
+///
+/// `client` must be a clone of the handle in `AppState`, with which it shares
+/// the queues of pending deliveries and replies.
+pub async fn run_event_loop(
+    mut eventloop: EventLoop,
+    client: MqttClient,
+    data: web::Data<AppState>,
+    mut transports: watch::Receiver<Transport>,
+    mut brokers: Brokers,
+    mut fallbacks: mpsc::Receiver<Broker>,
+) {
+    info!("Starting MQTT event loop...");
+    // Send time and delivery sender of publishes awaiting PubAck/PubComp,
+    // by packet id.
+    let mut in_flight: HashMap<u16, (Instant, Option<oneshot::Sender<Delivery>>)> = HashMap::new();
+    let mut backoff = Backoff::new(data.config.mqtt.reconnect.clone());
+    // Whether the active broker sent a ConnAck since the last failure. A
+    // broker that drops an established connection is retried first, one
+    // that cannot be connected to is failed over from.
+    let mut established = false;
+    data.connection
+        .send_modify(|status| status.broker = Some(brokers.active().clone()));
+    data.metrics.record_active_broker(&brokers);
+    loop {
+        // A poll cancelled here may have written half a packet, which does
+        // not matter as the connection is dropped right after.
+        let event = tokio::select! {
+            event = eventloop.poll() => Ok(event),
+            Ok(()) = transports.changed() => Err(Restart::Renewed),
+            Some(broker) = fallbacks.recv() => Err(Restart::Fallback(broker)),
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(restart) => {
+                let reason = {
+                    // Held so no publish enters the request channel between
+                    // discarding its requests and their delivery senders.
+                    let mut queued = client.queued.lock().unwrap_or_else(|e| e.into_inner());
+                    let reason = match restart {
+                        Restart::Renewed => {
+                            info!("Reconnecting to MQTT broker with renewed TLS certificates");
+                            eventloop.reconnect_with(transports.borrow_and_update().clone());
+                            "reconnecting with renewed TLS certificates".to_string()
+                        }
+                        Restart::Fallback(broker) => {
+                            info!(
+                                "Preferred MQTT broker {} is reachable again, falling back to it",
+                                broker
+                            );
+                            eventloop.connect_to(&data.config.mqtt, &broker);
+                            brokers.switch_to(&broker);
+                            data.metrics.record_broker_switch("fallback", &brokers);
+                            format!("falling back to MQTT broker {}", broker)
+                        }
+                    };
+                    queued.clear();
+                    reason
+                };
+                in_flight.clear();
+                established = false;
+                data.connection.send_modify(|status| {
+                    status.broker = Some(brokers.active().clone());
+                    status.on_error(reason);
+                });
+                data.metrics.mqtt_connected.set(0);
+                data.metrics.mqtt_reconnects.inc();
+                continue;
+            }
+        };
+
+        match event {
+            Ok(Notification::ConnAck(code)) => {
+                backoff.on_connected();
+                established = true;
+                info!("Connected to MQTT broker {}: {}", brokers.active(), code);
+                data.connection.send_modify(ConnectionStatus::on_connack);
+                data.metrics.mqtt_connected.set(1);
+                data.metrics.mqtt_circuit_open.set(0);
+                if let Err(e) = client.announce_online() {
+                    error!("Failed to publish birth message: {}", e);
+                }
+                subscribe_topics(&client, &data.states);
+            }
+            Ok(Notification::Sent(pkid)) => {
+                // Retransmissions after a reconnect keep the original send
+                // time and were already taken from the queue.
+                if in_flight.contains_key(&pkid) {
+                    continue;
+                }
+                let delivery = client.next_queued();
+                if pkid == 0 {
+                    // QoS 0 is never acknowledged: writing it is all there is.
+                    if let Some(delivery) = delivery {
+                        let _ = delivery.send(Ok(()));
+                    }
+                } else {
+                    in_flight.insert(pkid, (Instant::now(), delivery));
+                }
+            }
+            Ok(Notification::Acked(pkid, result)) => {
+                if let Some((sent, delivery)) = in_flight.remove(&pkid) {
+                    data.metrics
+                        .puback_latency
+                        .observe(sent.elapsed().as_secs_f64());
+                    if let Some(delivery) = delivery {
+                        // The HTTP request may have given up already.
+                        let _ = delivery.send(result);
+                    }
+                }
+            }
+            Ok(Notification::Publish {
+                topic,
+                payload,
+                correlation,
+                content_type,
+            }) if client.response_topic.as_ref() == Some(&topic) => match correlation {
+                Some(correlation) => {
+                    let reply = Reply {
+                        payload,
+                        content_type,
+                    };
+                    if !client.resolve_reply(&correlation, reply) {
+                        debug!(
+                            "Ignoring reply {} on '{}': no request is waiting for it",
+                            hex::encode(&correlation),
+                            topic
+                        );
+                    }
+                }
+                None => warn!("Ignoring reply on '{}' without correlation data", topic),
+            },
+            Ok(Notification::Publish { topic, payload, .. }) => {
+                let updated = data.states.update(&topic, &payload);
+                if updated.is_empty() {
+                    debug!("Ignoring message on unexpected topic '{}'", topic);
+                }
+                for (device, state) in updated {
+                    if state == DoorState::Unknown {
+                        warn!(
+                            "Device '{}' reported unrecognised state {:?}",
+                            device,
+                            String::from_utf8_lossy(&payload)
+                        );
+                    } else {
+                        info!("Device '{}' is now {}", device, state.as_str());
+                    }
+                }
+            }
+            Ok(Notification::Other(notification)) => {
+                debug!("MQTT notification: {}", notification);
+            }
+            Err(e) => {
+                let delay = backoff.on_failure(&e);
+                let circuit_open = backoff.is_circuit_open();
+                // A broker that drops an established connection is retried,
+                // one that cannot be connected to is failed over from.
+                let failover = if established {
+                    None
+                } else {
+                    let failed = brokers.active().clone();
+                    brokers.fail_over().cloned().inspect(|next| {
+                        // After a round over every broker, the outage
+                        // summaries take over.
+                        if backoff.failures() as usize <= brokers.all().len() {
+                            warn!(
+                                "Cannot connect to MQTT broker {}, failing over to {}",
+                                failed, next
+                            );
+                        } else {
+                            debug!("Failing over from MQTT broker {} to {}", failed, next);
+                        }
+                    })
+                };
+                established = false;
+                // Marked disconnected first, so requests stop publishing.
+                data.connection.send_modify(|status| {
+                    status.broker = Some(brokers.active().clone());
+                    status.on_error(e.to_string());
+                    status.on_backoff(backoff.failures(), delay, circuit_open);
+                });
+                {
+                    // The session is not resumed: requests not yet written
+                    // and publishes not yet acknowledged are dropped with
+                    // it, so their HTTP requests fail now rather than time
+                    // out. The lock is held so no publish enters the request
+                    // channel between discarding its requests and their
+                    // delivery senders.
+                    let mut queued = client.queued.lock().unwrap_or_else(|e| e.into_inner());
+                    match &failover {
+                        Some(next) => eventloop.connect_to(&data.config.mqtt, next),
+                        None => eventloop.clean(),
+                    }
+                    queued.clear();
+                }
+                in_flight.clear();
+                if failover.is_some() {
+                    data.metrics.record_broker_switch("failover", &brokers);
+                }
+                data.metrics.mqtt_connected.set(0);
+                data.metrics.mqtt_reconnects.inc();
+                data.metrics.mqtt_circuit_open.set(i64::from(circuit_open));
+                tokio::time::sleep(delay).await;
+                data.connection.send_modify(ConnectionStatus::on_attempt);
+            }
+        }
+    }
+}
+
+/// Subscriptions do not survive a clean session, so this runs on every ConnAck.
+/// `try_subscribe` is used because awaiting the request channel from inside
+/// the event loop would deadlock once it is full.
+fn subscribe_topics(client: &MqttClient, states: &DeviceStates) {
+    for topic in states.topics() {
+        match client.try_subscribe(topic) {
+            Ok(()) => info!("Subscribing to state topic '{}'", topic),
+            Err(e) => error!("Failed to subscribe to state topic '{}': {}", topic, e),
+        }
+    }
+    if let Some(topic) = &client.response_topic {
+        match client.try_subscribe(topic) {
+            Ok(()) => info!("Subscribing to response topic '{}'", topic),
+            Err(e) => error!("Failed to subscribe to response topic '{}': {}", topic, e),
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use crate::config::ReconnectConfig;
+use log::{debug, error, info, warn};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+/// Consecutive connection failures of the event loop: how long to wait
+/// before each retry and whether the circuit is open. Only the first failure
+/// of an outage is logged as it happens; the following ones are summarized
+/// every `summary_interval`.
+pub struct Backoff {
+    config: ReconnectConfig,
+    /// Failures since the last ConnAck.
+    failures: u32,
+    /// First failure of the ongoing outage.
+    outage_started: Option<Instant>,
+    last_summary: Instant,
+    failures_since_summary: u32,
+}
+
+impl Backoff {
+    pub fn new(config: ReconnectConfig) -> Self {
+        Backoff {
+            config,
+            failures: 0,
+            outage_started: None,
+            last_summary: Instant::now(),
+            failures_since_summary: 0,
+        }
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn is_circuit_open(&self) -> bool {
+        self.failures >= self.config.circuit_threshold
+    }
+
+    /// Records a failed connection and returns how long to wait before the
+    /// next attempt.
+    pub fn on_failure(&mut self, error: &dyn Display) -> Duration {
+        self.failures += 1;
+        let delay = self.delay();
+        let now = Instant::now();
+        match self.outage_started {
+            None => {
+                error!(
+                    "MQTT connection error: {}. Retrying in {}",
+                    error,
+                    humantime::format_duration(delay)
+                );
+                self.outage_started = Some(now);
+                self.last_summary = now;
+                self.failures_since_summary = 0;
+            }
+            Some(started) => {
+                debug!(
+                    "MQTT connection attempt {} failed: {}",
+                    self.failures, error
+                );
+                self.failures_since_summary += 1;
+                let since_summary = now.duration_since(self.last_summary);
+                if since_summary >= self.config.summary_interval {
+                    warn!(
+                        "MQTT broker unreachable for {}: {} failed attempt(s) in the last {}, \
+                         last error: {}. Next attempt in {}",
+                        whole_seconds(now.duration_since(started)),
+                        self.failures_since_summary,
+                        whole_seconds(since_summary),
+                        error,
+                        humantime::format_duration(delay)
+                    );
+                    self.last_summary = now;
+                    self.failures_since_summary = 0;
+                }
+            }
+        }
+        if self.failures == self.config.circuit_threshold {
+            warn!(
+                "MQTT circuit open after {} consecutive failures: actions fail with 503 \
+                 until the broker is reachable again",
+                self.failures
+            );
+        }
+        delay
+    }
+
+    /// Starts over after a ConnAck, logging how long the outage lasted.
+    pub fn on_connected(&mut self) {
+        if let Some(started) = self.outage_started.take() {
+            info!(
+                "MQTT broker reachable again after {} and {} failed attempt(s)",
+                whole_seconds(started.elapsed()),
+                self.failures
+            );
+        }
+        self.failures = 0;
+    }
+
+    /// `initial_delay` doubled for every failure after the first, up to
+    /// `max_delay`, with equal jitter: a random point in the upper half, so
+    /// replicas spread their retries without ever retrying immediately.
+    fn delay(&self) -> Duration {
+        let doublings = (self.failures - 1).min(31);
+        let ceiling = self
+            .config
+            .initial_delay
+            .saturating_mul(1 << doublings)
+            .min(self.config.max_delay);
+        let half = ceiling / 2;
+        let delay = half + half.mul_f64(random_fraction());
+        Duration::from_millis(delay.as_millis() as u64)
+    }
+}
+
+/// Uniform in `[0, 1]`; the midpoint if no randomness is available.
+fn random_fraction() -> f64 {
+    let mut bytes = [0; 4];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        return 0.5;
+    }
+    f64::from(u32::from_be_bytes(bytes)) / f64::from(u32::MAX)
+}
+
+fn whole_seconds(duration: Duration) -> humantime::FormattedDuration {
+    humantime::format_duration(Duration::from_secs(duration.as_secs()))
+}
@@ -0,0 +1,646 @@
+use crate::audit::{self, AuditEntry, MqttOutcome};
+use crate::auth::{self, Identity};
+use crate::config::DeviceConfig;
+use crate::error::ApiError;
+use crate::health::{self, CircuitState};
+use crate::metrics;
+use crate::mqtt::{PendingReply, Reply};
+use crate::state::{DeviceStatus, DoorState};
+use crate::AppState;
+use actix_web::http::header::{HeaderValue, HOST, LOCATION, RETRY_AFTER};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+
+/// Something a client can ask a device to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Trigger,
+    Open,
+    Close,
+}
+
+impl Action {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::Trigger => "trigger",
+            Action::Open => "open",
+            Action::Close => "close",
+        }
+    }
+}
+
+/// Longest `?wait=` a client may ask for.
+const MAX_WAIT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ActionParams {
+    /// Send an open/close command even when the door state is unknown.
+    #[serde(default)]
+    force: bool,
+    /// Hold the response until the device reports the resulting state.
+    #[serde(default, with = "humantime_serde")]
+    wait: Option<Duration>,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(
+        web::QueryConfig::default()
+            .error_handler(|err, _| ApiError::bad_request(err.to_string()).into()),
+    )
+    // Only the action is parsed from the path; anything else is unknown.
+    .app_data(web::PathConfig::default().error_handler(|err, req| {
+        match req.match_info().get("action") {
+            Some(action) => ApiError::not_found(format!(
+                "Unknown action '{}'; expected trigger, open or close",
+                action
+            )),
+            None => ApiError::not_found(err.to_string()),
+        }
+        .into()
+    }))
+    .route("/garage", web::post().to(trigger_garage))
+    .route("/devices", web::get().to(list_devices))
+    // Registered before the action route so GET requests reach it.
+    .route("/devices/{name}/state", web::get().to(device_state))
+    .route("/devices/{name}/{action}", web::post().to(device_action))
+    .route("/health", web::get().to(health::live))
+    .route("/health/live", web::get().to(health::live))
+    .route("/health/ready", web::get().to(health::ready))
+    .route("/audit", web::get().to(audit::query))
+    .route("/metrics", web::get().to(metrics::render));
+}
+
+/// Answers a request on the plain HTTP listener with a redirect to the same
+/// URL over HTTPS: 308 rather than 301, so a POST stays a POST.
+pub async fn redirect_to_https(
+    req: HttpRequest,
+    https_port: u16,
+) -> Result<HttpResponse, ApiError> {
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(without_port)
+        .filter(|host| !host.is_empty())
+        .ok_or_else(|| ApiError::bad_request("Host header is required"))?;
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+    let location = if https_port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, https_port, path)
+    };
+    Ok(HttpResponse::PermanentRedirect()
+        .insert_header((LOCATION, location))
+        .finish())
+}
+
+/// The host of a `host[:port]` Host header, brackets kept for IPv6.
+fn without_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        host.find(']').map_or(host, |end| &host[..=end])
+    } else {
+        host.split(':').next().unwrap_or(host)
+    }
+}
+
+/// Legacy route: triggers the default device.
+async fn trigger_garage(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    params: web::Query<ActionParams>,
+) -> Result<HttpResponse, ApiError> {
+    info!("Received garage door trigger request");
+
+    let device = data.config.default_device();
+    audited_action(&req, &data, device, Action::Trigger, &params).await
+}
+
+async fn device_action(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    path: web::Path<(String, Action)>,
+    params: web::Query<ActionParams>,
+) -> Result<HttpResponse, ApiError> {
+    let (name, action) = path.into_inner();
+    info!("Received {} request for device '{}'", action.as_str(), name);
+
+    let device = find_device(&data, &name)?;
+    audited_action(&req, &data, device, action, &params).await
+}
+
+/// Runs an action for a request and records it in the audit log, whether it
+/// succeeded or not.
+async fn audited_action(
+    req: &HttpRequest,
+    data: &AppState,
+    device: &DeviceConfig,
+    action: Action,
+    params: &ActionParams,
+) -> Result<HttpResponse, ApiError> {
+    let mut entry = AuditEntry::new(
+        req,
+        &data.config.http.trusted_proxies,
+        &device.name,
+        action.as_str(),
+    );
+    let result = async {
+        auth::authorize(req, &device.name, action.as_str())?;
+        check_rate(req, data)?;
+        run_action(data, device, action, params, &mut entry.mqtt).await
+    }
+    .await;
+    entry.finish(&result);
+    data.audit.record(&entry).await;
+    result
+}
+
+async fn run_action(
+    data: &AppState,
+    device: &DeviceConfig,
+    action: Action,
+    params: &ActionParams,
+    mqtt: &mut MqttOutcome,
+) -> Result<HttpResponse, ApiError> {
+    if let Some(state) = check_intent(data, device, action, params.force)? {
+        info!(
+            "Device '{}' is already {}, not publishing",
+            device.name,
+            state.as_str()
+        );
+        return Ok(HttpResponse::Ok().json(serde_json::json!({
+            "status": "success",
+            "message": format!("Device '{}' is already {}", device.name, state.as_str()),
+            "state": state
+        })));
+    }
+
+    let confirmation = match params.wait {
+        Some(timeout) => Some(Confirmation::prepare(data, device, action, timeout)?),
+        None => None,
+    };
+
+    let cooldown_started = data.limits.start_cooldown(device).map_err(|limited| {
+        warn!(
+            "Refusing to {} device '{}' during its cooldown ({} command(s) refused), retry in {}s",
+            action.as_str(),
+            device.name,
+            limited.rejected,
+            limited.retry_after_secs()
+        );
+        data.metrics
+            .rate_limited
+            .with_label_values(&["cooldown"])
+            .inc();
+        let message = format!(
+            "Device '{}' is cooling down; retry in {}s",
+            device.name,
+            limited.retry_after_secs()
+        );
+        limited.into_error(message)
+    })?;
+
+    let pending = publish(data, device, action, device.payload_for(action), mqtt)
+        .await
+        .inspect_err(|_| {
+            // A command that may still be delivered keeps the device cooling down.
+            if matches!(*mqtt, MqttOutcome::NotSent | MqttOutcome::Failed) {
+                data.limits.cancel_cooldown(device, cooldown_started);
+            }
+        })?;
+    let reply = match (pending, device.response_timeout) {
+        (Some(pending), Some(timeout)) => Some(wait_for_reply(device, pending, timeout).await?),
+        _ => None,
+    };
+
+    let mut body = match confirmation {
+        Some(confirmation) => confirmation.wait(&device.name).await?,
+        None => serde_json::json!({
+            "status": "success",
+            "message": match action {
+                Action::Trigger => format!("Device '{}' triggered", device.name),
+                _ => format!("Sent {} command to device '{}'", action.as_str(), device.name),
+            }
+        }),
+    };
+    if let Some(reply) = reply {
+        body["response"] = reply;
+    }
+    Ok(HttpResponse::Ok().json(body))
+}
+
+/// Waits for the device to answer a command sent with a response topic.
+async fn wait_for_reply(
+    device: &DeviceConfig,
+    pending: PendingReply,
+    timeout: Duration,
+) -> Result<serde_json::Value, ApiError> {
+    match pending.wait(timeout).await {
+        Some(reply) => {
+            info!("Device '{}' replied to its command", device.name);
+            Ok(reply_body(reply))
+        }
+        None => {
+            warn!(
+                "Device '{}' did not reply within {}",
+                device.name,
+                humantime::format_duration(timeout)
+            );
+            Err(ApiError::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                format!(
+                    "Device '{}' received the command but did not reply within {}",
+                    device.name,
+                    humantime::format_duration(timeout)
+                ),
+            ))
+        }
+    }
+}
+
+/// A reply payload as JSON when it is JSON, as a string otherwise. A content
+/// type other than JSON keeps e.g. `1` from turning into a number.
+fn reply_body(reply: Reply) -> serde_json::Value {
+    let json_allowed = reply
+        .content_type
+        .as_deref()
+        .is_none_or(|content_type| content_type.contains("json"));
+    json_allowed
+        .then(|| serde_json::from_slice(&reply.payload).ok())
+        .flatten()
+        .unwrap_or_else(|| String::from_utf8_lossy(&reply.payload).into_owned().into())
+}
+
+/// Applies the rate limit of the request's API key or client certificate, or
+/// of its client IP when authentication is disabled.
+fn check_rate(req: &HttpRequest, data: &AppState) -> Result<(), ApiError> {
+    let identity = req.extensions().get::<Identity>().map(Identity::to_string);
+    let client = match identity {
+        Some(identity) => identity,
+        None => format!(
+            "client {}",
+            auth::client_ip(req, &data.config.http.trusted_proxies)
+                .map_or_else(|| "unknown".to_string(), |ip| ip.to_string())
+        ),
+    };
+    data.limits.check_rate(&client).map_err(|limited| {
+        warn!(
+            "Rate limited {} ({} request(s) rejected in a row), retry in {}s",
+            client,
+            limited.rejected,
+            limited.retry_after_secs()
+        );
+        data.metrics
+            .rate_limited
+            .with_label_values(&["rate_limit"])
+            .inc();
+        let message = format!(
+            "Too many requests; retry in {}s",
+            limited.retry_after_secs()
+        );
+        limited.into_error(message)
+    })
+}
+
+/// Decides whether an open/close command has to be published given the
+/// tracked door state. Returns the current state if the door is already
+/// there (or on its way), and an error if the state is unknown.
+fn check_intent(
+    data: &AppState,
+    device: &DeviceConfig,
+    action: Action,
+    force: bool,
+) -> Result<Option<DoorState>, ApiError> {
+    let (target, moving) = match action {
+        Action::Open => (DoorState::Open, DoorState::Opening),
+        Action::Close => (DoorState::Closed, DoorState::Closing),
+        Action::Trigger => return Ok(None),
+    };
+    let state = data
+        .states
+        .get(&device.name)
+        .map(|status| status.state)
+        .unwrap_or_default();
+
+    if state == target || state == moving {
+        return Ok(Some(state));
+    }
+    if state == DoorState::Unknown && !force {
+        warn!(
+            "Refusing to {} device '{}' in unknown state",
+            action.as_str(),
+            device.name
+        );
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!(
+                "State of device '{}' is unknown; retry with force=true to send the command anyway",
+                device.name
+            ),
+        )
+        .with("state", state));
+    }
+    Ok(None)
+}
+
+/// Waits for a device to report the state an action should lead to.
+struct Confirmation {
+    receiver: watch::Receiver<DeviceStatus>,
+    /// `None` when a toggle was sent from an unknown state: any final state
+    /// reported afterwards confirms it.
+    expected: Option<DoorState>,
+    sent_at: SystemTime,
+    timeout: Duration,
+}
+
+impl Confirmation {
+    /// Captures the state before publishing so only later reports count.
+    fn prepare(
+        data: &AppState,
+        device: &DeviceConfig,
+        action: Action,
+        timeout: Duration,
+    ) -> Result<Confirmation, ApiError> {
+        if timeout > MAX_WAIT {
+            return Err(ApiError::bad_request(format!(
+                "wait must not exceed {}",
+                humantime::format_duration(MAX_WAIT)
+            )));
+        }
+        let receiver = data.states.watch(&device.name).ok_or_else(|| {
+            ApiError::bad_request(format!(
+                "Cannot wait for device '{}': it has no state topic configured",
+                device.name
+            ))
+        })?;
+
+        let current = receiver.borrow().state;
+        let expected = match action {
+            Action::Open => Some(DoorState::Open),
+            Action::Close => Some(DoorState::Closed),
+            Action::Trigger => match current {
+                DoorState::Open | DoorState::Opening => Some(DoorState::Closed),
+                DoorState::Closed | DoorState::Closing => Some(DoorState::Open),
+                DoorState::Unknown => None,
+            },
+        };
+        Ok(Confirmation {
+            receiver,
+            expected,
+            sent_at: SystemTime::now(),
+            timeout,
+        })
+    }
+
+    async fn wait(mut self, name: &str) -> Result<serde_json::Value, ApiError> {
+        let expected = self.expected;
+        let sent_at = self.sent_at;
+        let confirmed = tokio::time::timeout(
+            self.timeout,
+            self.receiver.wait_for(|status| {
+                status.updated_at.is_some_and(|at| at >= sent_at)
+                    && match expected {
+                        Some(state) => status.state == state,
+                        None => matches!(status.state, DoorState::Open | DoorState::Closed),
+                    }
+            }),
+        )
+        .await
+        .ok()
+        .and_then(|result| result.ok().map(|status| *status));
+
+        if let Some(status) = confirmed {
+            info!(
+                "Device '{}' confirmed it is {}",
+                name,
+                status.state.as_str()
+            );
+            return Ok(serde_json::json!({
+                "status": "success",
+                "message": format!("Device '{}' is now {}", name, status.state.as_str()),
+                "state": status.state
+            }));
+        }
+
+        let last = *self.receiver.borrow();
+        warn!(
+            "Device '{}' did not confirm within {}, last state {}",
+            name,
+            humantime::format_duration(self.timeout),
+            last.state.as_str()
+        );
+        Err(ApiError::new(
+            StatusCode::GATEWAY_TIMEOUT,
+            format!(
+                "Device '{}' did not report {} within {}",
+                name,
+                expected.map_or("a new state", DoorState::as_str),
+                humantime::format_duration(self.timeout)
+            ),
+        )
+        .with("state", last.state)
+        .with("updated_at", last.updated_at.map(format_timestamp)))
+    }
+}
+
+/// Publishes `payload` to the device's topic and waits for the broker to
+/// acknowledge it. Returns the pending reply if the device was asked for one.
+async fn publish(
+    data: &AppState,
+    device: &DeviceConfig,
+    action: Action,
+    payload: &str,
+    mqtt: &mut MqttOutcome,
+) -> Result<Option<PendingReply>, ApiError> {
+    let failed = |status: StatusCode, reason: String| {
+        ApiError::new(
+            status,
+            format!(
+                "Failed to {} device '{}': {}",
+                action.as_str(),
+                device.name,
+                reason
+            ),
+        )
+    };
+
+    let (connected, circuit, next_attempt_at) = {
+        let status = data.connection.borrow();
+        (status.connected, status.circuit, status.next_attempt_at)
+    };
+    if circuit != CircuitState::Closed {
+        warn!(
+            "Not publishing to '{}': MQTT broker unavailable, circuit open",
+            device.topic
+        );
+        record_publish(data, device, "error");
+        *mqtt = MqttOutcome::Failed;
+        let error = failed(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "broker unavailable".to_string(),
+        );
+        // Half-open means an attempt is under way: no wait is known.
+        let Some(next_attempt_at) = next_attempt_at else {
+            return Err(error);
+        };
+        let wait = next_attempt_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        let seconds = (wait.as_secs() + u64::from(wait.subsec_nanos() > 0)).max(1);
+        return Err(error
+            .with("retry_after", seconds)
+            .with_header(RETRY_AFTER, HeaderValue::from(seconds)));
+    }
+    if !connected {
+        warn!(
+            "Not publishing to '{}': MQTT broker is not connected",
+            device.topic
+        );
+        record_publish(data, device, "error");
+        *mqtt = MqttOutcome::Failed;
+        return Err(failed(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "MQTT broker is not connected".to_string(),
+        ));
+    }
+
+    let published = data.mqtt_client.publish(device, payload).map_err(|e| {
+        error!("Failed to publish MQTT message: {}", e);
+        record_publish(data, device, "error");
+        *mqtt = MqttOutcome::Failed;
+        failed(StatusCode::SERVICE_UNAVAILABLE, e.to_string())
+    })?;
+
+    let timeout = data.config.mqtt.ack_timeout;
+    match tokio::time::timeout(timeout, published.delivery).await {
+        Ok(Ok(Ok(()))) => {
+            info!("Broker acknowledged MQTT message to '{}'", device.topic);
+            record_publish(data, device, "success");
+            *mqtt = MqttOutcome::Published;
+            Ok(published.reply)
+        }
+        Ok(Ok(Err(reason))) => {
+            error!(
+                "Broker refused MQTT message to '{}': {}",
+                device.topic, reason
+            );
+            record_publish(data, device, "rejected");
+            *mqtt = MqttOutcome::Failed;
+            Err(failed(
+                StatusCode::BAD_GATEWAY,
+                format!("the broker refused the message ({})", reason),
+            ))
+        }
+        Ok(Err(_)) => {
+            warn!(
+                "Connection to MQTT broker lost before the message to '{}' was acknowledged",
+                device.topic
+            );
+            record_publish(data, device, "error");
+            *mqtt = MqttOutcome::Unacknowledged;
+            Err(failed(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "the connection to the MQTT broker was lost before the message was acknowledged"
+                    .to_string(),
+            ))
+        }
+        Err(_) => {
+            let connected = data.connection.borrow().connected;
+            warn!(
+                "No acknowledgement for MQTT message to '{}' within {} (broker {})",
+                device.topic,
+                humantime::format_duration(timeout),
+                if connected {
+                    "connected"
+                } else {
+                    "disconnected"
+                }
+            );
+            record_publish(data, device, "timeout");
+            *mqtt = MqttOutcome::Unacknowledged;
+            let (status, reason) = if connected {
+                (
+                    StatusCode::GATEWAY_TIMEOUT,
+                    "the broker did not acknowledge the message",
+                )
+            } else {
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "the connection to the MQTT broker was lost",
+                )
+            };
+            Err(failed(
+                status,
+                format!(
+                    "{} within {}; it may still be delivered",
+                    reason,
+                    humantime::format_duration(timeout)
+                ),
+            ))
+        }
+    }
+}
+
+fn record_publish(data: &AppState, device: &DeviceConfig, outcome: &str) {
+    data.metrics
+        .mqtt_publishes
+        .with_label_values(&[&device.topic, outcome])
+        .inc();
+}
+
+/// Lists the devices the request's credential has any scope on.
+async fn list_devices(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let default = &data.config.default_device().name;
+    let extensions = req.extensions();
+    let identity = extensions.get::<Identity>();
+    let devices: Vec<_> = data
+        .config
+        .devices
+        .iter()
+        .filter(|device| identity.is_none_or(|identity| identity.can_access(&device.name)))
+        .map(|device| {
+            serde_json::json!({
+                "name": device.name,
+                "topic": device.topic,
+                "qos": device.qos,
+                "retain": device.retain,
+                "state_topic": device.state_topic,
+                "default": &device.name == default,
+            })
+        })
+        .collect();
+
+    HttpResponse::Ok().json(serde_json::json!({ "devices": devices }))
+}
+
+async fn device_state(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    find_device(&data, &name)?;
+    auth::authorize(&req, &name, "state")?;
+    let status = data.states.get(&name).ok_or_else(|| {
+        ApiError::not_found(format!("Device '{}' has no state topic configured", name))
+    })?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "device": name.as_str(),
+        "state": status.state,
+        "updated_at": status.updated_at.map(format_timestamp),
+    })))
+}
+
+fn find_device<'a>(data: &'a AppState, name: &str) -> Result<&'a DeviceConfig, ApiError> {
+    data.config
+        .device(name)
+        .ok_or_else(|| ApiError::not_found(format!("Unknown device '{}'", name)))
+}
+
+fn format_timestamp(at: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(at).to_string()
+}
@@ -0,0 +1,119 @@
+use crate::config::Config;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::SystemTime;
+use tokio::sync::watch;
+
+/// Door position as reported on a device's state topic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DoorState {
+    Open,
+    Closed,
+    Opening,
+    Closing,
+    #[default]
+    Unknown,
+}
+
+impl DoorState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DoorState::Open => "open",
+            DoorState::Closed => "closed",
+            DoorState::Opening => "opening",
+            DoorState::Closing => "closing",
+            DoorState::Unknown => "unknown",
+        }
+    }
+
+    /// Parses a state payload: either a bare word (`open`, `CLOSED`, ...) or
+    /// a JSON object with a `state` field.
+    pub fn parse(payload: &[u8]) -> DoorState {
+        let text = String::from_utf8_lossy(payload);
+        let text = text.trim();
+
+        if text.starts_with('{') {
+            return serde_json::from_str::<serde_json::Value>(text)
+                .ok()
+                .and_then(|value| value.get("state")?.as_str().map(DoorState::from_word))
+                .unwrap_or(DoorState::Unknown);
+        }
+        DoorState::from_word(text)
+    }
+
+    fn from_word(word: &str) -> DoorState {
+        match word.trim().to_ascii_lowercase().as_str() {
+            "open" | "opened" => DoorState::Open,
+            "closed" | "close" => DoorState::Closed,
+            "opening" => DoorState::Opening,
+            "closing" => DoorState::Closing,
+            _ => DoorState::Unknown,
+        }
+    }
+}
+
+/// Latest reported state of a device and when it was received.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeviceStatus {
+    pub state: DoorState,
+    pub updated_at: Option<SystemTime>,
+}
+
+/// Latest state of every device with a state topic, fed by the MQTT event loop.
+pub struct DeviceStates {
+    devices: HashMap<String, watch::Sender<DeviceStatus>>,
+    by_topic: HashMap<String, Vec<String>>,
+}
+
+impl DeviceStates {
+    pub fn new(config: &Config) -> Self {
+        let mut devices = HashMap::new();
+        let mut by_topic: HashMap<String, Vec<String>> = HashMap::new();
+        for device in &config.devices {
+            if let Some(topic) = &device.state_topic {
+                let (tx, _) = watch::channel(DeviceStatus::default());
+                devices.insert(device.name.clone(), tx);
+                by_topic
+                    .entry(topic.clone())
+                    .or_default()
+                    .push(device.name.clone());
+            }
+        }
+        DeviceStates { devices, by_topic }
+    }
+
+    /// State topics to subscribe to.
+    pub fn topics(&self) -> impl Iterator<Item = &str> {
+        self.by_topic.keys().map(String::as_str)
+    }
+
+    /// Current status of a device, or `None` if it has no state topic.
+    pub fn get(&self, device: &str) -> Option<DeviceStatus> {
+        self.devices.get(device).map(|tx| *tx.borrow())
+    }
+
+    /// Receiver notified on every state report of a device.
+    pub fn watch(&self, device: &str) -> Option<watch::Receiver<DeviceStatus>> {
+        self.devices.get(device).map(watch::Sender::subscribe)
+    }
+
+    /// Records a payload received on `topic`, returning the devices it updated.
+    pub fn update(&self, topic: &str, payload: &[u8]) -> Vec<(&str, DoorState)> {
+        let Some(names) = self.by_topic.get(topic) else {
+            return Vec::new();
+        };
+        let state = DoorState::parse(payload);
+        let status = DeviceStatus {
+            state,
+            updated_at: Some(SystemTime::now()),
+        };
+        names
+            .iter()
+            .map(|name| {
+                self.devices[name].send_replace(status);
+                (name.as_str(), state)
+            })
+            .collect()
+    }
+}
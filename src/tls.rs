@@ -0,0 +1,678 @@
+use crate::auth::{CertificateNames, ClientCertificates};
+use crate::config::{HttpTlsConfig, MqttTlsConfig, TlsMode};
+use crate::metrics::Metrics;
+use log::{error, info, warn};
+use p12_keystore::error::Error as P12Error;
+use p12_keystore::KeyStore;
+use pkcs8::{pkcs5, EncryptedPrivateKeyInfo, PrivateKeyInfo};
+use rumqttc::{TlsConfiguration, Transport};
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, DistinguishedName, InconsistentKeys,
+    RootCertStore, ServerConfig, SignatureScheme,
+};
+use rustls_pemfile::{certs, private_key};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+
+/// How often certificate expiry is checked against the warning thresholds.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Label of a passphrase-protected PKCS#8 key in PEM.
+const ENCRYPTED_KEY_LABEL: &str = "ENCRYPTED PRIVATE KEY";
+
+/// Why the certificates or key for the broker connection cannot be used.
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("cannot read {}: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("cannot decode {}: {message}", path.display())]
+    Invalid { path: PathBuf, message: String },
+    #[error("no certificate found in {}", path.display())]
+    NoCertificate { path: PathBuf },
+    #[error("invalid CA certificate in {}: {source}", path.display())]
+    InvalidCaCertificate {
+        path: PathBuf,
+        source: rustls::Error,
+    },
+    #[error("no private key found in {}", path.display())]
+    NoPrivateKey { path: PathBuf },
+    #[error("{} is encrypted but no passphrase is configured", path.display())]
+    PassphraseRequired { path: PathBuf },
+    #[error("wrong passphrase for {}", path.display())]
+    WrongPassphrase { path: PathBuf },
+    #[error("{} holds no private key matching one of its certificates", path.display())]
+    NoMatchingKey { path: PathBuf },
+    #[error(
+        "the private key in {} does not belong to the certificate in {}",
+        key.display(),
+        cert.display()
+    )]
+    KeyMismatch { cert: PathBuf, key: PathBuf },
+    #[error("cannot use the client certificate: {0}")]
+    ClientAuth(rustls::Error),
+}
+
+/// Builds the transport for the configured TLS mode from the certificate
+/// files as they are now.
+pub fn transport(config: &MqttTlsConfig) -> Result<Transport, TlsError> {
+    let tls_config = match config.mode {
+        TlsMode::Mutual => load_tls_config(config)?,
+        TlsMode::Server => load_server_tls_config(&config.ca_cert)?,
+        TlsMode::Disabled => return Ok(Transport::tcp()),
+    };
+    Ok(Transport::tls_with_config(TlsConfiguration::Rustls(
+        Arc::new(tls_config),
+    )))
+}
+
+/// TLS config for mutual TLS: verifies the broker against `ca_cert` and
+/// authenticates with the client certificate.
+pub fn load_tls_config(config: &MqttTlsConfig) -> Result<ClientConfig, TlsError> {
+    let root_store = load_root_store(&config.ca_cert)?;
+    let (client_certs, client_key) = load_client_identity(config)?;
+
+    ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_client_auth_cert(client_certs, client_key)
+        .map_err(|e| match e {
+            rustls::Error::InconsistentKeys(InconsistentKeys::KeyMismatch) => {
+                let (cert, key) = match &config.client_p12 {
+                    Some(bundle) => (bundle, bundle),
+                    None => (&config.client_cert, &config.client_key),
+                };
+                TlsError::KeyMismatch {
+                    cert: cert.clone(),
+                    key: key.clone(),
+                }
+            }
+            e => TlsError::ClientAuth(e),
+        })
+}
+
+/// TLS config that only verifies the broker against `ca_path`.
+pub fn load_server_tls_config(ca_path: &Path) -> Result<ClientConfig, TlsError> {
+    Ok(ClientConfig::builder()
+        .with_root_certificates(load_root_store(ca_path)?)
+        .with_no_client_auth())
+}
+
+fn load_root_store(ca_path: &Path) -> Result<RootCertStore, TlsError> {
+    let mut root_store = RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        root_store
+            .add(cert)
+            .map_err(|source| TlsError::InvalidCaCertificate {
+                path: ca_path.to_path_buf(),
+                source,
+            })?;
+    }
+    Ok(root_store)
+}
+
+/// The client certificate chain, leaf first, and its private key: from the
+/// PKCS#12 bundle if one is configured, else from the PEM files.
+fn load_client_identity(
+    config: &MqttTlsConfig,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), TlsError> {
+    let passphrase = config.key_passphrase.as_deref();
+    match &config.client_p12 {
+        Some(path) => load_pkcs12(path, passphrase),
+        None => Ok((
+            load_certs(&config.client_cert)?,
+            load_private_key(&config.client_key, passphrase)?,
+        )),
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, TlsError> {
+    std::fs::read(path).map_err(|source| TlsError::Read {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn invalid(path: &Path, message: impl std::fmt::Display) -> TlsError {
+    TlsError::Invalid {
+        path: path.to_path_buf(),
+        message: message.to_string(),
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let contents = read_file(path)?;
+    let certs = certs(&mut contents.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(path, e))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate {
+            path: path.to_path_buf(),
+        });
+    }
+    Ok(certs)
+}
+
+/// Reads a PEM private key, decrypting it if it is an encrypted PKCS#8 key.
+fn load_private_key(
+    path: &Path,
+    passphrase: Option<&str>,
+) -> Result<PrivateKeyDer<'static>, TlsError> {
+    let contents = read_file(path)?;
+    let text = String::from_utf8_lossy(&contents);
+    if let Some(pem) = pem_block(&text, ENCRYPTED_KEY_LABEL) {
+        let passphrase = passphrase.ok_or_else(|| TlsError::PassphraseRequired {
+            path: path.to_path_buf(),
+        })?;
+        return decrypt_private_key(path, pem, passphrase);
+    }
+    if text.contains("Proc-Type: 4,ENCRYPTED") {
+        return Err(invalid(
+            path,
+            "legacy encrypted PEM keys are not supported, convert the key with `openssl pkcs8 -topk8`",
+        ));
+    }
+    private_key(&mut contents.as_slice())
+        .map_err(|e| invalid(path, e))?
+        .ok_or_else(|| TlsError::NoPrivateKey {
+            path: path.to_path_buf(),
+        })
+}
+
+/// The first `label` block of a PEM file, armor included.
+fn pem_block<'a>(text: &'a str, label: &str) -> Option<&'a str> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let start = text.find(&begin)?;
+    let length = text[start..].find(&end)? + end.len();
+    Some(&text[start..start + length])
+}
+
+fn decrypt_private_key(
+    path: &Path,
+    pem: &str,
+    passphrase: &str,
+) -> Result<PrivateKeyDer<'static>, TlsError> {
+    let wrong_passphrase = || TlsError::WrongPassphrase {
+        path: path.to_path_buf(),
+    };
+    let (_, der) = pkcs8::der::pem::decode_vec(pem.as_bytes()).map_err(|e| invalid(path, e))?;
+    let encrypted =
+        EncryptedPrivateKeyInfo::try_from(der.as_slice()).map_err(|e| invalid(path, e))?;
+    // A wrong passphrase shows as bad padding, which pkcs5 reports as
+    // `EncryptFailed`, or rarely as a key that does not decode.
+    let decrypted = encrypted.decrypt(passphrase).map_err(|e| match e {
+        pkcs8::Error::EncryptedPrivateKey(
+            pkcs5::Error::DecryptFailed | pkcs5::Error::EncryptFailed,
+        )
+        | pkcs8::Error::Asn1(_) => wrong_passphrase(),
+        e => invalid(path, e),
+    })?;
+    PrivateKeyInfo::try_from(decrypted.as_bytes()).map_err(|_| wrong_passphrase())?;
+    Ok(PrivateKeyDer::Pkcs8(decrypted.as_bytes().to_vec().into()))
+}
+
+/// Reads the first key of a PKCS#12 bundle and the certificate chain
+/// belonging to it.
+fn load_pkcs12(
+    path: &Path,
+    passphrase: Option<&str>,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), TlsError> {
+    let contents = read_file(path)?;
+    let keystore =
+        KeyStore::from_pkcs12(&contents, passphrase.unwrap_or("")).map_err(|e| match e {
+            P12Error::MacError(_) | P12Error::UnpadError if passphrase.is_none() => {
+                TlsError::PassphraseRequired {
+                    path: path.to_path_buf(),
+                }
+            }
+            P12Error::MacError(_) | P12Error::UnpadError => TlsError::WrongPassphrase {
+                path: path.to_path_buf(),
+            },
+            e => invalid(path, e),
+        })?;
+    let (_, chain) = keystore
+        .private_key_chain()
+        .ok_or_else(|| TlsError::NoMatchingKey {
+            path: path.to_path_buf(),
+        })?;
+    let certs = chain
+        .chain()
+        .iter()
+        .map(|cert| CertificateDer::from(cert.as_der().to_vec()))
+        .collect();
+    Ok((certs, PrivateKeyDer::Pkcs8(chain.key().to_vec().into())))
+}
+
+/// A certificate file in use.
+#[derive(Debug, Clone)]
+pub struct LoadedCertificate {
+    /// Subject of the first certificate, the leaf of a chain.
+    pub subject: String,
+    /// Earliest expiry (`notAfter`) among the file's certificates.
+    pub not_after: SystemTime,
+}
+
+impl LoadedCertificate {
+    fn parse(path: &Path, chain: &[CertificateDer]) -> Result<LoadedCertificate, TlsError> {
+        let mut loaded: Option<LoadedCertificate> = None;
+        for cert in chain {
+            let (_, parsed) =
+                x509_parser::parse_x509_certificate(cert).map_err(|e| invalid(path, e))?;
+            let not_after = parsed.validity().not_after.timestamp();
+            let not_after = SystemTime::UNIX_EPOCH + Duration::from_secs(not_after.max(0) as u64);
+            match &mut loaded {
+                Some(loaded) => loaded.not_after = loaded.not_after.min(not_after),
+                None => {
+                    loaded = Some(LoadedCertificate {
+                        subject: parsed.subject().to_string(),
+                        not_after,
+                    })
+                }
+            }
+        }
+        loaded.ok_or_else(|| TlsError::NoCertificate {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Whole days left before expiry; negative once expired.
+    pub fn days_until_expiry(&self) -> i64 {
+        match self.not_after.duration_since(SystemTime::now()) {
+            Ok(left) => (left.as_secs() / SECONDS_PER_DAY) as i64,
+            Err(e) => -((e.duration().as_secs() / SECONDS_PER_DAY) as i64) - 1,
+        }
+    }
+}
+
+/// The certificates in use by their `certificate` metric label, replaced
+/// whenever they are reloaded.
+#[derive(Default)]
+pub struct Certificates {
+    loaded: RwLock<BTreeMap<&'static str, LoadedCertificate>>,
+}
+
+impl Certificates {
+    /// Reads the certificates of `config`, logging and exporting their expiry.
+    pub fn load(&self, config: &MqttTlsConfig, metrics: &Metrics) {
+        let mut loaded = BTreeMap::new();
+        for (name, path) in certificates(config) {
+            // A PKCS#12 bundle is only readable with its passphrase.
+            let chain = match name {
+                "client" => load_client_identity(config).map(|(chain, _)| chain),
+                _ => load_certs(path),
+            };
+            match chain.and_then(|chain| LoadedCertificate::parse(path, &chain)) {
+                Ok(cert) => {
+                    info!(
+                        "TLS {} certificate '{}' expires {} ({} days)",
+                        name,
+                        cert.subject,
+                        humantime::format_rfc3339_seconds(cert.not_after),
+                        cert.days_until_expiry()
+                    );
+                    metrics.record_certificate_expiry(name, cert.not_after);
+                    loaded.insert(name, cert);
+                }
+                Err(e) => warn!("Cannot read expiry of {} certificate: {}", name, e),
+            }
+        }
+        *self.loaded.write().unwrap_or_else(|e| e.into_inner()) = loaded;
+    }
+
+    pub fn all(&self) -> BTreeMap<&'static str, LoadedCertificate> {
+        self.loaded
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/// Logs a warning when a certificate comes within one of `warning_days` of
+/// its expiry, once per threshold, and an error every check once it expired.
+/// Checks hourly; a renewed certificate starts over.
+pub async fn monitor_expiry(certificates: Arc<Certificates>, warning_days: Vec<u32>) {
+    // Smallest threshold warned about, per certificate and expiry.
+    let mut warned: HashMap<(&str, SystemTime), u32> = HashMap::new();
+    let mut interval = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        for (name, cert) in certificates.all() {
+            let days = cert.days_until_expiry();
+            if days < 0 {
+                error!(
+                    "TLS {} certificate '{}' expired on {}",
+                    name,
+                    cert.subject,
+                    humantime::format_rfc3339_seconds(cert.not_after)
+                );
+                continue;
+            }
+            let Some(threshold) = warning_days
+                .iter()
+                .copied()
+                .filter(|threshold| days < i64::from(*threshold))
+                .min()
+            else {
+                continue;
+            };
+            let key = (name, cert.not_after);
+            if warned.get(&key).is_some_and(|warned| *warned <= threshold) {
+                continue;
+            }
+            warn!(
+                "TLS {} certificate '{}' expires in {} day(s), on {}",
+                name,
+                cert.subject,
+                days,
+                humantime::format_rfc3339_seconds(cert.not_after)
+            );
+            warned.insert(key, threshold);
+        }
+    }
+}
+
+/// Certificates the transport is built from, by their `certificate` label
+/// in the expiry metric.
+pub fn certificates(config: &MqttTlsConfig) -> Vec<(&'static str, &Path)> {
+    match config.mode {
+        TlsMode::Mutual => vec![
+            ("ca", &config.ca_cert),
+            (
+                "client",
+                config.client_p12.as_deref().unwrap_or(&config.client_cert),
+            ),
+        ],
+        TlsMode::Server => vec![("ca", &config.ca_cert)],
+        TlsMode::Disabled => Vec::new(),
+    }
+}
+
+/// Every file the transport is built from.
+fn watched_files(config: &MqttTlsConfig) -> Vec<&Path> {
+    let mut files: Vec<_> = certificates(config)
+        .into_iter()
+        .map(|(_, path)| path)
+        .collect();
+    if config.mode == TlsMode::Mutual && config.client_p12.is_none() {
+        files.push(&config.client_key);
+    }
+    files
+}
+
+/// SHA-256 over the contents of the certificate files. Contents rather than
+/// modification times, as Kubernetes updates secrets by swapping symlinks.
+async fn fingerprint(files: &[&Path]) -> std::io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    for path in files {
+        let contents = tokio::fs::read(path).await?;
+        hasher.update(Sha256::digest(&contents));
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Calls `reload` every `interval` once the contents of `files` differ from
+/// those last reloaded successfully. `reload` returns whether it succeeded;
+/// failures are retried on the next check.
+async fn reload_on_change(files: &[&Path], interval: Duration, mut reload: impl FnMut() -> bool) {
+    let mut loaded = fingerprint(files).await.ok();
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    // The first tick completes immediately.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let current = match fingerprint(files).await {
+            Ok(current) => current,
+            Err(e) => {
+                warn!("Cannot read TLS certificates to check for changes: {}", e);
+                continue;
+            }
+        };
+        if loaded != Some(current) && reload() {
+            loaded = Some(current);
+        }
+    }
+}
+
+/// Checks the certificate files every `reload_interval` and sends a transport
+/// built from them whenever their contents changed. Files that cannot be
+/// loaded, e.g. a certificate rotated before its key, are retried on the next
+/// check while the current transport stays in use.
+pub async fn watch_certificates(
+    config: MqttTlsConfig,
+    certificates: Arc<Certificates>,
+    metrics: Arc<Metrics>,
+    transports: watch::Sender<Transport>,
+) {
+    let files = watched_files(&config);
+    if files.is_empty() || config.reload_interval.is_zero() {
+        return;
+    }
+    info!(
+        "Checking TLS certificates for changes every {}",
+        humantime::format_duration(config.reload_interval)
+    );
+    reload_on_change(&files, config.reload_interval, || {
+        match transport(&config) {
+            Ok(transport) => {
+                info!("Reloaded TLS certificates");
+                certificates.load(&config, &metrics);
+                transports.send_replace(transport);
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "TLS certificates changed but cannot be loaded, keeping the current ones: {}",
+                    e
+                );
+                false
+            }
+        }
+    })
+    .await
+}
+
+/// The certificate of the HTTPS listener. Handshakes pick up a renewed
+/// certificate as soon as it is loaded, without restarting the server.
+#[derive(Debug)]
+pub struct ServerCertificate {
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ServerCertificate {
+    pub fn load(config: &HttpTlsConfig) -> Result<ServerCertificate, TlsError> {
+        let provider = ServerConfig::builder().crypto_provider().clone();
+        let current = RwLock::new(Self::read(config, &provider)?);
+        Ok(ServerCertificate { provider, current })
+    }
+
+    fn read(
+        config: &HttpTlsConfig,
+        provider: &CryptoProvider,
+    ) -> Result<Arc<CertifiedKey>, TlsError> {
+        let (Some(cert_path), Some(key_path)) = (&config.cert, &config.key) else {
+            unreachable!("HTTPS is enabled without a certificate and key");
+        };
+        let certs = load_certs(cert_path)?;
+        let key = load_private_key(key_path, config.key_passphrase.as_deref())?;
+        let loaded = LoadedCertificate::parse(cert_path, &certs)?;
+        let certified = CertifiedKey::from_der(certs, key, provider).map_err(|e| match e {
+            rustls::Error::InconsistentKeys(InconsistentKeys::KeyMismatch) => {
+                TlsError::KeyMismatch {
+                    cert: cert_path.clone(),
+                    key: key_path.clone(),
+                }
+            }
+            e => invalid(key_path, e),
+        })?;
+        info!(
+            "HTTPS certificate '{}' expires {} ({} days)",
+            loaded.subject,
+            humantime::format_rfc3339_seconds(loaded.not_after),
+            loaded.days_until_expiry()
+        );
+        Ok(Arc::new(certified))
+    }
+
+    fn reload(&self, config: &HttpTlsConfig) -> Result<(), TlsError> {
+        let certified = Self::read(config, &self.provider)?;
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = certified;
+        Ok(())
+    }
+
+    /// Server config for the HTTPS listener, serving the current certificate
+    /// and verifying client certificates if `client_ca` is set.
+    pub fn server_config(
+        self: &Arc<Self>,
+        config: &HttpTlsConfig,
+        clients: Arc<ClientCertificates>,
+    ) -> Result<ServerConfig, TlsError> {
+        let builder = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .expect("the default crypto provider supports the default protocol versions");
+        let builder = match &config.client_ca {
+            Some(ca_path) => {
+                let roots = Arc::new(load_root_store(ca_path)?);
+                let mut verifier =
+                    WebPkiClientVerifier::builder_with_provider(roots, self.provider.clone());
+                if !config.require_client_cert {
+                    verifier = verifier.allow_unauthenticated();
+                }
+                let verifier = verifier.build().map_err(|e| invalid(ca_path, e))?;
+                builder.with_client_cert_verifier(Arc::new(KnownClientVerifier {
+                    inner: verifier,
+                    clients,
+                }))
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(builder.with_cert_resolver(self.clone()))
+    }
+}
+
+impl ResolvesServerCert for ServerCertificate {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(
+            self.current
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+        )
+    }
+}
+
+/// Verifies client certificates against `http.tls.client_ca`, then refuses
+/// those matching none of `auth.clients` so unknown clients fail the
+/// handshake.
+#[derive(Debug)]
+struct KnownClientVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    clients: Arc<ClientCertificates>,
+}
+
+impl ClientCertVerifier for KnownClientVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.inner.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.inner.client_auth_mandatory()
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.inner.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.inner
+            .verify_client_cert(end_entity, intermediates, now)
+            .inspect_err(|e| warn!("Refused HTTPS client certificate: {}", e))?;
+        let names = CertificateNames::parse(end_entity).ok_or(
+            rustls::Error::InvalidCertificate(CertificateError::BadEncoding),
+        )?;
+        if self.clients.identify(&names).is_none() {
+            warn!(
+                "Refused HTTPS client certificate '{}' (SAN: {}): it matches no auth.clients entry",
+                names.subject,
+                names.sans.join(", ")
+            );
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Checks the HTTPS certificate and key every `reload_interval` and serves
+/// them once they changed and load.
+pub async fn watch_server_certificate(config: HttpTlsConfig, certificate: Arc<ServerCertificate>) {
+    let (Some(cert), Some(key)) = (&config.cert, &config.key) else {
+        return;
+    };
+    if config.reload_interval.is_zero() {
+        return;
+    }
+    info!(
+        "Checking the HTTPS certificate for changes every {}",
+        humantime::format_duration(config.reload_interval)
+    );
+    reload_on_change(&[cert, key], config.reload_interval, || {
+        match certificate.reload(&config) {
+            Ok(()) => {
+                info!("Reloaded HTTPS certificate");
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "HTTPS certificate changed but cannot be loaded, keeping the current one: {}",
+                    e
+                );
+                false
+            }
+        }
+    })
+    .await
+}